@@ -0,0 +1,146 @@
+use rustfft::num_complex::Complex;
+use std::f64::consts::{LN_2, PI};
+
+use crate::configcreation::{BiquadParameters, PeakingWidth, ShelfSteepness};
+
+// coefficients normalized to a0 = 1, calculated like CamillaDSP does after the Audio EQ Cookbook
+#[derive(Debug, Clone, Copy)]
+pub struct BiquadCoefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+impl BiquadCoefficients {
+    pub fn new(parameters: &BiquadParameters, samplerate: f64) -> Self {
+        let omega = |freq: f32| 2.0 * PI * freq as f64 / samplerate;
+        let ampl = |gain: f32| 10f64.powf(gain as f64 / 40.0);
+        match parameters {
+            BiquadParameters::Highpass { freq, q } => {
+                let (sn, cs) = omega(*freq).sin_cos();
+                let alpha = sn / (2.0 * *q as f64);
+                BiquadCoefficients::normalized(
+                    [(1.0 + cs) / 2.0, -(1.0 + cs), (1.0 + cs) / 2.0],
+                    [1.0 + alpha, -2.0 * cs, 1.0 - alpha],
+                )
+            }
+            BiquadParameters::Lowpass { freq, q } => {
+                let (sn, cs) = omega(*freq).sin_cos();
+                let alpha = sn / (2.0 * *q as f64);
+                BiquadCoefficients::normalized(
+                    [(1.0 - cs) / 2.0, 1.0 - cs, (1.0 - cs) / 2.0],
+                    [1.0 + alpha, -2.0 * cs, 1.0 - alpha],
+                )
+            }
+            BiquadParameters::Peaking(width) => {
+                let (freq, gain, alpha) = match width {
+                    PeakingWidth::Q { freq, q, gain } => {
+                        (*freq, *gain, omega(*freq).sin() / (2.0 * *q as f64))
+                    }
+                    PeakingWidth::Bandwidth {
+                        freq,
+                        bandwidth,
+                        gain,
+                    } => {
+                        let (omega, bandwidth) = (omega(*freq), *bandwidth as f64);
+                        let alpha =
+                            omega.sin() * (LN_2 / 2.0 * bandwidth * omega / omega.sin()).sinh();
+                        (*freq, *gain, alpha)
+                    }
+                };
+                let (ampl, cs) = (ampl(gain), omega(freq).cos());
+                BiquadCoefficients::normalized(
+                    [1.0 + alpha * ampl, -2.0 * cs, 1.0 - alpha * ampl],
+                    [1.0 + alpha / ampl, -2.0 * cs, 1.0 - alpha / ampl],
+                )
+            }
+            BiquadParameters::Lowshelf(steepness) | BiquadParameters::Highshelf(steepness) => {
+                let (freq, gain) = match steepness {
+                    ShelfSteepness::Q { freq, gain, .. }
+                    | ShelfSteepness::Slope { freq, gain, .. } => (*freq, *gain),
+                };
+                let (sn, cs) = omega(freq).sin_cos();
+                let ampl = ampl(gain);
+                let alpha = sn / (2.0 * shelf_q(steepness) as f64);
+                let beta = 2.0 * ampl.sqrt() * alpha;
+                // the high shelf mirrors the low shelf on the frequency axis
+                let cs = match parameters {
+                    BiquadParameters::Lowshelf(_) => cs,
+                    _ => -cs,
+                };
+                let sign = match parameters {
+                    BiquadParameters::Lowshelf(_) => 1.0,
+                    _ => -1.0,
+                };
+                BiquadCoefficients::normalized(
+                    [
+                        ampl * ((ampl + 1.0) - (ampl - 1.0) * cs + beta),
+                        sign * 2.0 * ampl * ((ampl - 1.0) - (ampl + 1.0) * cs),
+                        ampl * ((ampl + 1.0) - (ampl - 1.0) * cs - beta),
+                    ],
+                    [
+                        (ampl + 1.0) + (ampl - 1.0) * cs + beta,
+                        sign * -2.0 * ((ampl - 1.0) + (ampl + 1.0) * cs),
+                        (ampl + 1.0) + (ampl - 1.0) * cs - beta,
+                    ],
+                )
+            }
+            BiquadParameters::HighshelfFO { freq, gain } => {
+                let (ampl, tn) = (ampl(*gain), (omega(*freq) / 2.0).tan());
+                BiquadCoefficients::normalized(
+                    [ampl * tn + ampl.powi(2), ampl * tn - ampl.powi(2), 0.0],
+                    [ampl * tn + 1.0, ampl * tn - 1.0, 0.0],
+                )
+            }
+            BiquadParameters::LowshelfFO { freq, gain } => {
+                let (ampl, tn) = (ampl(*gain), (omega(*freq) / 2.0).tan());
+                BiquadCoefficients::normalized(
+                    [ampl.powi(2) * tn + ampl, ampl.powi(2) * tn - ampl, 0.0],
+                    [tn + ampl, tn - ampl, 0.0],
+                )
+            }
+            BiquadParameters::HighpassFO { freq } => {
+                let tn = (omega(*freq) / 2.0).tan();
+                BiquadCoefficients::normalized([1.0, -1.0, 0.0], [tn + 1.0, tn - 1.0, 0.0])
+            }
+            BiquadParameters::LowpassFO { freq } => {
+                let tn = (omega(*freq) / 2.0).tan();
+                BiquadCoefficients::normalized([tn, tn, 0.0], [tn + 1.0, tn - 1.0, 0.0])
+            }
+        }
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        BiquadCoefficients {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    pub fn response(&self, freq: f64, samplerate: f64) -> Complex<f64> {
+        let z = Complex::from_polar(1.0, -2.0 * PI * freq / samplerate);
+        let z2 = z * z;
+        (self.b0 + self.b1 * z + self.b2 * z2) / (1.0 + self.a1 * z + self.a2 * z2)
+    }
+}
+
+// shelf slopes are given in dB per octave, 12 dB equal a Q of 1/sqrt(2)
+pub fn shelf_q(steepness: &ShelfSteepness) -> f32 {
+    match steepness {
+        ShelfSteepness::Q { q, .. } => *q,
+        ShelfSteepness::Slope { slope, gain, .. } => {
+            let ampl = 10f32.powf(gain / 40.0);
+            let slope = slope / 12.0;
+            1.0 / ((ampl + 1.0 / ampl) * (1.0 / slope - 1.0) + 2.0).sqrt()
+        }
+    }
+}
+
+// bandwidth in octaves
+pub fn bandwidth_q(bandwidth: f32) -> f32 {
+    1.0 / (2.0 * (std::f32::consts::LN_2 / 2.0 * bandwidth).sinh())
+}
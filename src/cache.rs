@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug)]
+pub struct Cache {
+    dir: Option<PathBuf>,
+    ttl: Duration,
+    refresh: bool,
+}
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new(24, false)
+    }
+}
+impl Cache {
+    pub fn new(ttl_hours: u64, refresh: bool) -> Self {
+        Cache {
+            dir: dirs::cache_dir().map(|dir| dir.join("autoeq2camilladsp")),
+            ttl: Duration::from_secs(ttl_hours * 60 * 60),
+            refresh,
+        }
+    }
+
+    pub fn refresh(&self) -> bool {
+        self.refresh
+    }
+
+    pub fn load(&self, url: &str) -> Option<CacheEntry> {
+        let path = self.path(url)?;
+        let content = fs::read_to_string(&path).ok()?;
+        let age = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or(Duration::MAX);
+        Some(CacheEntry {
+            content,
+            fresh: age < self.ttl,
+        })
+    }
+
+    pub fn store(&self, url: &str, content: &str) -> Result<()> {
+        let (Some(dir), Some(path)) = (&self.dir, self.path(url)) else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir).context("Could not create the cache directory.")?;
+        // write to a temporary file first so concurrent runs never read half a file
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, content).context("Could not write to the cache.")?;
+        fs::rename(&temporary_path, &path).context("Could not write to the cache.")?;
+        Ok(())
+    }
+
+    // readable file name with a hash of the full url to keep it unique
+    fn path(&self, url: &str) -> Option<PathBuf> {
+        let name: String = url
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '.' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+        let hash = url.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{:016x}-{}", hash, name)))
+    }
+}
+
+pub struct CacheEntry {
+    pub content: String,
+    pub fresh: bool,
+}
@@ -1,96 +1,688 @@
-use crate::scraping::CorrectionFilterSet;
+use crate::crossfeed::{Crossfeed, Hrir};
+use crate::devices::{load_devices, Devices, DevicesFile};
+use crate::export::{OutputFormat, Stage, StageFilter};
+use crate::fir::{impulse_response, read_wav, resample, FirSettings, ImpulseFile, ImpulseFormat};
+use crate::report::{FileExistsError, InputError, Warnings};
+use crate::response::{gain_db, FrequencyResponse};
+use crate::scraping::{CorrectionFilterSet, GraphicEq};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap},
-    fmt,
-    fs::File,
-    io::{Read, Write},
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-static POWCHUMOY: &[u8] = include_bytes!("data/pow_chu_moy.yml");
-static MPM: &[u8] = include_bytes!("data/mpm.yml");
-static NATURAL: &[u8] = include_bytes!("data/natural.yml");
+// in mm per ms, the same value CamillaDSP uses for delays in mm
+const SPEED_OF_SOUND: f32 = 343.0;
+// for FIR filters of configurations without devices
+const DEFAULT_FIR_SAMPLERATE: usize = 44100;
+// frequencies from 20 Hz to 20 kHz the peak gain is searched at
+const PEAK_GAIN_POINTS: usize = 200;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum CamillaDspVersion {
+    #[value(name = "1", alias = "v1")]
+    V1,
+    #[value(name = "2", alias = "v2")]
+    V2,
+    #[default]
+    #[value(name = "3", alias = "v3")]
+    V3,
+}
+impl fmt::Display for CamillaDspVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+// the JSON input takes the same values as the command line
+impl<'de> Deserialize<'de> for CamillaDspVersion {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        CamillaDspVersion::from_str(&version, true).map_err(serde::de::Error::custom)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Configuration {
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    mixers: HashMap<String, Mixer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Devices>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    mixers: BTreeMap<String, Mixer>,
+    #[serde(default, deserialize_with = "deserialize_filters")]
     filters: BTreeMap<String, Filter>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    processors: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
     pipeline: Vec<PipelineStep>,
+    #[serde(skip)]
+    impulse_files: Vec<ImpulseFile>,
+    #[serde(skip)]
+    version: CamillaDspVersion,
+    // relative impulse files of a read configuration belong to its directory
+    #[serde(skip)]
+    directory: PathBuf,
 }
 impl Configuration {
     fn new() -> Self {
         Configuration {
-            mixers: HashMap::new(),
+            title: None,
+            description: None,
+            devices: None,
+            mixers: BTreeMap::new(),
             filters: BTreeMap::new(),
+            processors: BTreeMap::new(),
             pipeline: Vec::new(),
+            impulse_files: Vec::new(),
+            version: CamillaDspVersion::default(),
+            directory: PathBuf::new(),
         }
     }
-    // for future use
-    #[allow(dead_code)]
     fn add_mixer(&mut self, mixer_name: String, mixer: Mixer) {
         self.mixers.insert(mixer_name, mixer);
     }
-    fn add_mixers(&mut self, mixers: HashMap<String, Mixer>) {
+    fn add_mixers(&mut self, mixers: BTreeMap<String, Mixer>) {
         self.mixers.extend(mixers);
     }
-    // for future use
-    #[allow(dead_code)]
     fn add_filter(&mut self, filter_name: String, filter: Filter) {
         self.filters.insert(filter_name, filter);
     }
     fn add_filters(&mut self, filters: BTreeMap<String, Filter>) {
         self.filters.extend(filters);
     }
-    fn add_pipeline_step(&mut self, pipeline_step: PipelineStep) {
-        self.pipeline.push(pipeline_step);
+    fn insert_pipeline_step(&mut self, position: usize, pipeline_step: PipelineStep) {
+        self.pipeline.insert(position, pipeline_step);
     }
     fn add_pipeline_steps(&mut self, pipeline_steps: &mut Vec<PipelineStep>) {
         self.pipeline.append(pipeline_steps);
     }
+    pub fn filter_names(&self) -> Vec<String> {
+        self.filters.keys().cloned().collect()
+    }
+    // a configuration sent over the websocket has no file its impulse files could be relative to
+    pub fn resolve_impulse_paths(&mut self, directory: &Path) -> Result<()> {
+        for filter in self.filters.values_mut() {
+            if let Filter::Conv {
+                parameters:
+                    ConvParameters::Raw { filename, .. } | ConvParameters::Wav { filename, .. },
+            } = filter
+            {
+                let path = directory
+                    .join(&filename)
+                    .canonicalize()
+                    .with_context(|| format!("The impulse file {} does not exist.", filename))?;
+                *filename = path.display().to_string();
+            }
+        }
+        Ok(())
+    }
+    // filters and pipeline steps without a response, like processors, are reported as errors
+    fn stages(&self) -> Result<Vec<Stage>> {
+        self.pipeline
+            .iter()
+            .map(|step| match step {
+                PipelineStep::Mixer { name, .. } => {
+                    let mixer = self
+                        .mixers
+                        .get(name)
+                        .with_context(|| format!("The mixer {} does not exist.", name))?;
+                    let mut outputs = vec![Vec::new(); mixer.channels.out];
+                    for mapping in mixer.mapping.iter().filter(|mapping| !mapping.mute) {
+                        if let Some(output) = outputs.get_mut(mapping.dest) {
+                            *output = mapping
+                                .sources
+                                .iter()
+                                .filter(|source| !source.mute)
+                                .map(|source| (source.channel, source.linear_gain()))
+                                .collect();
+                        }
+                    }
+                    Ok(Stage::Mix(outputs))
+                }
+                PipelineStep::Filter {
+                    channel,
+                    channels,
+                    names,
+                    ..
+                } => {
+                    let filters = names
+                        .iter()
+                        .map(|name| match self.filters.get(name) {
+                            Some(Filter::Biquad { parameters }) => {
+                                Ok(StageFilter::Biquad(parameters.clone()))
+                            }
+                            Some(Filter::Gain { parameters }) => {
+                                let linear = parameters.scale == Some(GainScale::Linear);
+                                Ok(match linear || parameters.inverted || parameters.mute {
+                                    true => StageFilter::Linear(parameters.linear_gain()),
+                                    false => StageFilter::Gain(parameters.gain),
+                                })
+                            }
+                            Some(Filter::Conv { parameters }) => {
+                                Ok(StageFilter::Impulse(self.impulse_samples(parameters)?))
+                            }
+                            Some(Filter::Delay { parameters }) => {
+                                Ok(StageFilter::Delay(parameters.clone()))
+                            }
+                            Some(Filter::Other(_)) => Err(InputError(format!(
+                                "The filter {} is not supported, only Biquad, Gain, Conv and Delay filters are.",
+                                name
+                            ))
+                            .into()),
+                            None => Err(anyhow!("The filter {} does not exist.", name)),
+                        })
+                        .collect::<Result<_>>()?;
+                    Ok(Stage::Filter {
+                        channels: channel
+                            .iter()
+                            .chain(channels.iter().flatten())
+                            .copied()
+                            .collect(),
+                        filters,
+                    })
+                }
+                PipelineStep::Other(_) => Err(InputError(
+                    "Only Mixer and Filter pipeline steps are supported.".to_string(),
+                )
+                .into()),
+            })
+            .collect()
+    }
+
+    // generated impulse files are not written yet, the others are read relative to the
+    // configuration file
+    fn impulse_samples(&self, parameters: &ConvParameters) -> Result<Vec<f32>> {
+        let filename = match parameters {
+            ConvParameters::Raw { filename, .. } | ConvParameters::Wav { filename, .. } => filename,
+        };
+        if let Some(impulse_file) = self
+            .impulse_files
+            .iter()
+            .find(|impulse_file| &impulse_file.filename == filename)
+        {
+            return Ok(impulse_file.samples.clone());
+        }
+        let bytes = fs::read(self.directory.join(filename))
+            .with_context(|| format!("The impulse file {} could not be read.", filename))?;
+        match parameters {
+            // both formats are 32 bit float little endian
+            ConvParameters::Raw { .. } => Ok(bytes
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+                .collect()),
+            ConvParameters::Wav { channel, .. } => read_wav(&bytes)
+                .with_context(|| format!("The impulse file {} could not be read.", filename))?
+                .channels
+                .get(*channel)
+                .cloned()
+                .ok_or_else(|| {
+                    InputError(format!(
+                        "The impulse file {} has no channel {}.",
+                        filename, channel
+                    ))
+                    .into()
+                }),
+        }
+    }
+
+    // every generated configuration starts with two channels, samples need the sample rate
+    // of the devices
+    pub fn frequency_response(&self, freqs: &[f64]) -> Result<FrequencyResponse> {
+        let samplerate = match &self.devices {
+            Some(devices) => devices.samplerate as f64,
+            None => return Err(anyhow!("The configuration has no sample rate.")),
+        };
+        FrequencyResponse::new(&self.stages()?, 2, freqs, samplerate)
+    }
+
+    // the highest gain in dB from any input to any output and its frequency
+    pub fn peak_gain(&self) -> Result<(f64, f64)> {
+        let freqs: Vec<f64> = (0..PEAK_GAIN_POINTS)
+            .map(|i| 20.0 * 1000f64.powf(i as f64 / (PEAK_GAIN_POINTS - 1) as f64))
+            .collect();
+        let response = self.frequency_response(&freqs)?;
+        Ok(response
+            .transfer
+            .iter()
+            .flatten()
+            .flat_map(|path| {
+                path.iter()
+                    .copied()
+                    .map(gain_db)
+                    .zip(response.freqs.iter().copied())
+            })
+            .fold((f64::NEG_INFINITY, 0.0), |peak, point| {
+                match point.0 > peak.0 {
+                    true => point,
+                    false => peak,
+                }
+            }))
+    }
+
+    // crossfeed presets start and end with two channels and only use their own mixers and filters
+    pub fn validate_crossfeed(&self) -> Result<()> {
+        if !self.processors.is_empty() {
+            return Err(anyhow!("processors can not be used in a crossfeed"));
+        }
+        if let Some(name) = self.filters.keys().find(|name| is_correction_filter(name)) {
+            return Err(anyhow!(
+                "the filter name {} is used by the correction",
+                name
+            ));
+        }
+        let mut channels = 2;
+        for step in self.pipeline.iter() {
+            match step {
+                PipelineStep::Mixer { name, .. } => {
+                    let mixer = self
+                        .mixers
+                        .get(name)
+                        .ok_or_else(|| anyhow!("the mixer {} is not defined", name))?;
+                    if mixer.channels.r#in != channels {
+                        return Err(anyhow!(
+                            "the mixer {} has {} input channels but gets {}",
+                            name,
+                            mixer.channels.r#in,
+                            channels
+                        ));
+                    }
+                    let valid = mixer.mapping.iter().all(|mapping| {
+                        mapping.dest < mixer.channels.out
+                            && mapping
+                                .sources
+                                .iter()
+                                .all(|source| source.channel < mixer.channels.r#in)
+                    });
+                    if !valid {
+                        return Err(anyhow!("the mixer {} maps channels it does not have", name));
+                    }
+                    channels = mixer.channels.out;
+                }
+                PipelineStep::Filter {
+                    channel,
+                    channels: step_channels,
+                    names,
+                    ..
+                } => {
+                    let step_channels: Vec<usize> = channel
+                        .iter()
+                        .chain(step_channels.iter().flatten())
+                        .copied()
+                        .collect();
+                    if let Some(channel) =
+                        step_channels.iter().find(|channel| **channel >= channels)
+                    {
+                        return Err(anyhow!(
+                            "the filters {} are applied to channel {} of {}",
+                            names.join(", "),
+                            channel,
+                            channels
+                        ));
+                    }
+                    if let Some(name) = names.iter().find(|name| !self.filters.contains_key(*name))
+                    {
+                        return Err(anyhow!("the filter {} is not defined", name));
+                    }
+                }
+                PipelineStep::Other(_) => {
+                    return Err(anyhow!(
+                        "only Mixer and Filter steps can be used in a crossfeed"
+                    ))
+                }
+            }
+        }
+        if channels != 2 {
+            return Err(anyhow!(
+                "the pipeline ends with {} instead of 2 channels",
+                channels
+            ));
+        }
+        Ok(())
+    }
+
+    // removes the filters of an earlier correction and returns where it was in the pipeline
+    fn remove_correction(&mut self) -> Option<usize> {
+        self.filters.retain(|name, _| !is_correction_filter(name));
+        let mut position = None;
+        let mut pipeline = Vec::new();
+        for mut step in self.pipeline.drain(..) {
+            if let PipelineStep::Filter { names, .. } = &mut step {
+                let count = names.len();
+                names.retain(|name| !is_correction_filter(name));
+                if names.len() < count {
+                    position.get_or_insert(pipeline.len());
+                    if names.is_empty() {
+                        continue;
+                    }
+                }
+            }
+            pipeline.push(step);
+        }
+        self.pipeline = pipeline;
+        position
+    }
+    // fields unknown to the selected version are removed, new required ones are filled in,
+    // returns what was removed although it was set
+    fn set_version(&mut self, version: CamillaDspVersion) -> Result<Vec<String>> {
+        use CamillaDspVersion::*;
+        let previous = self.version;
+        self.version = version;
+        let mut removed = Vec::new();
+        if version == V1 {
+            if !self.processors.is_empty() {
+                return Err(InputError(format!(
+                    "CamillaDSP {} has no processors, the configuration can not be converted.",
+                    version
+                ))
+                .into());
+            }
+            if self.title.take().is_some() {
+                removed.push("the title".to_string());
+            }
+            if self.description.take().is_some() {
+                removed.push("the description".to_string());
+            }
+        }
+        // settings of patched configurations are kept where the version allows it
+        let scale = |scale: Option<GainScale>| match version {
+            V1 => None,
+            V2 | V3 => scale.or(Some(GainScale::Db)),
+        };
+        for (name, mixer) in self.mixers.iter_mut() {
+            if version == V1 && mixer.description.take().is_some() {
+                removed.push(format!("the description of the mixer {}", name));
+            }
+            if version < V3 && !mixer.options.is_empty() {
+                let options: Vec<String> = mixer.options.keys().cloned().collect();
+                removed.push(format!("{} of the mixer {}", options.join(", "), name));
+                mixer.options.clear();
+            }
+            for source in mixer
+                .mapping
+                .iter_mut()
+                .flat_map(|mapping| &mut mapping.sources)
+            {
+                if version == V1 && source.scale == Some(GainScale::Linear) {
+                    source.gain = linear_to_db(source.gain, &mut source.inverted, &mut source.mute);
+                }
+                source.scale = scale(source.scale);
+            }
+        }
+        let mut unknown_filters = Vec::new();
+        for (name, filter) in self.filters.iter_mut() {
+            match filter {
+                Filter::Gain { parameters } => {
+                    if version == V1 && parameters.scale == Some(GainScale::Linear) {
+                        parameters.gain = linear_to_db(
+                            parameters.gain,
+                            &mut parameters.inverted,
+                            &mut parameters.mute,
+                        );
+                    }
+                    parameters.scale = scale(parameters.scale)
+                }
+                Filter::Conv {
+                    parameters: ConvParameters::Raw { format, .. },
+                } => *format = format.for_version(version),
+                Filter::Delay { parameters } if version == V1 => {
+                    if let Some(delay) = parameters.milliseconds() {
+                        parameters.delay = delay;
+                        parameters.unit = DelayUnit::Ms;
+                    }
+                    if parameters.subsample.take() == Some(true) {
+                        removed.push(format!("the subsample delay of the filter {}", name));
+                    }
+                }
+                Filter::Delay { parameters } => {
+                    parameters.subsample = parameters.subsample.or(Some(false))
+                }
+                Filter::Other(_) => unknown_filters.push(name.clone()),
+                _ => (),
+            }
+        }
+        // version 1 can not bypass steps, so they are left out to keep the sound the same
+        if version == V1 {
+            let steps = self.pipeline.len();
+            self.pipeline.retain(|step| {
+                !matches!(
+                    step,
+                    PipelineStep::Mixer {
+                        bypassed: Some(true),
+                        ..
+                    } | PipelineStep::Filter {
+                        bypassed: Some(true),
+                        ..
+                    }
+                )
+            });
+            if self.pipeline.len() < steps {
+                removed.push(format!(
+                    "{} bypassed pipeline steps",
+                    steps - self.pipeline.len()
+                ));
+            }
+            if self.pipeline.iter().any(|step| {
+                matches!(
+                    step,
+                    PipelineStep::Mixer {
+                        description: Some(_),
+                        ..
+                    } | PipelineStep::Filter {
+                        description: Some(_),
+                        ..
+                    }
+                )
+            }) {
+                removed.push("the descriptions of the pipeline steps".to_string());
+            }
+        }
+        let unknown_steps = self
+            .pipeline
+            .iter()
+            .filter(|step| matches!(step, PipelineStep::Other(_)))
+            .count();
+        let bypassed = |bypassed: Option<bool>| match version {
+            V1 => None,
+            V2 | V3 => bypassed.or(Some(false)),
+        };
+        let description = |description: Option<String>| match version {
+            V1 => None,
+            V2 | V3 => description,
+        };
+        self.pipeline = self
+            .pipeline
+            .drain(..)
+            .flat_map(|step| match step {
+                PipelineStep::Mixer {
+                    name,
+                    description: step_description,
+                    bypassed: step_bypassed,
+                } => vec![PipelineStep::Mixer {
+                    name,
+                    description: description(step_description),
+                    bypassed: bypassed(step_bypassed),
+                }],
+                PipelineStep::Filter {
+                    channel,
+                    channels,
+                    names,
+                    description: step_description,
+                    bypassed: step_bypassed,
+                } => {
+                    let step_description = description(step_description);
+                    let step_bypassed = bypassed(step_bypassed);
+                    let channels: Vec<usize> = channel
+                        .into_iter()
+                        .chain(channels.into_iter().flatten())
+                        .collect();
+                    match version {
+                        // older versions need one step per channel
+                        V1 | V2 => channels
+                            .into_iter()
+                            .map(|channel| PipelineStep::Filter {
+                                channel: Some(channel),
+                                channels: None,
+                                names: names.clone(),
+                                description: step_description.clone(),
+                                bypassed: step_bypassed,
+                            })
+                            .collect(),
+                        V3 => vec![PipelineStep::Filter {
+                            channel: None,
+                            channels: Some(channels),
+                            names,
+                            description: step_description,
+                            bypassed: step_bypassed,
+                        }],
+                    }
+                }
+                step => vec![step],
+            })
+            .collect();
+
+        let mut warnings = Vec::new();
+        if !removed.is_empty() {
+            warnings.push(format!(
+                "Removed for CamillaDSP {}: {}.",
+                version,
+                removed.join(", ")
+            ));
+        }
+        if previous != version && !unknown_filters.is_empty() {
+            warnings.push(format!(
+                "The filters {} are not converted from CamillaDSP {} to {} and may need changes.",
+                unknown_filters.join(", "),
+                previous,
+                version
+            ));
+        }
+        if previous != version && unknown_steps > 0 {
+            warnings.push(format!(
+                "{} pipeline steps are not converted from CamillaDSP {} to {} and may need changes.",
+                unknown_steps, previous, version
+            ));
+        }
+        Ok(warnings)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// version 1 only takes gains in dB
+fn linear_to_db(gain: f32, inverted: &mut bool, mute: &mut bool) -> f32 {
+    if gain < 0.0 {
+        *inverted = !*inverted;
+    }
+    if gain == 0.0 {
+        *mute = true;
+        return 0.0;
+    }
+    20.0 * gain.abs().log10()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Mixer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
     pub channels: MixerChannels,
     pub mapping: Vec<MixerMapping>,
+    // further options like the labels of version 3 are passed through as they are
+    #[serde(flatten)]
+    pub options: BTreeMap<String, serde_yaml::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MixerMapping {
     pub dest: usize,
     pub sources: Vec<MixerSource>,
+    #[serde(default)]
     pub mute: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MixerSource {
     pub channel: usize,
     pub gain: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<GainScale>,
+    #[serde(default)]
     pub inverted: bool,
+    #[serde(default)]
     pub mute: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl MixerSource {
+    fn linear_gain(&self) -> f32 {
+        let gain = match self.scale {
+            Some(GainScale::Linear) => self.gain,
+            _ => 10f32.powf(self.gain / 20.0),
+        };
+        match self.inverted {
+            true => -gain,
+            false => gain,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MixerChannels {
     pub r#in: usize,
     pub out: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
 enum Filter {
-    Biquad { parameters: BiquadParameters },
-    Gain { parameters: GainParameters },
+    Biquad {
+        parameters: BiquadParameters,
+    },
+    Gain {
+        parameters: GainParameters,
+    },
+    Conv {
+        parameters: ConvParameters,
+    },
+    Delay {
+        parameters: DelayParameters,
+    },
+    // filters of patched configurations which are not created here are kept as they are
+    #[serde(untagged, skip_deserializing)]
+    Other(serde_yaml::Value),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ConvParameters {
+    Raw { filename: String, format: RawFormat },
+    Wav { filename: String, channel: usize },
+}
+
+// same names as the sample formats of the devices
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RawFormat {
+    #[serde(rename = "FLOAT32LE")]
+    Float32Le,
+    #[serde(rename = "F32_LE")]
+    AlsaF32Le,
+}
+impl RawFormat {
+    fn for_version(self, version: CamillaDspVersion) -> RawFormat {
+        match version {
+            CamillaDspVersion::V1 | CamillaDspVersion::V2 => RawFormat::Float32Le,
+            CamillaDspVersion::V3 => RawFormat::AlsaF32Le,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BiquadParameters {
-    // for future use
-    #[allow(dead_code)]
     Highpass {
         freq: f32,
         q: f32,
@@ -100,6 +692,8 @@ pub enum BiquadParameters {
         q: f32,
     },
     Peaking(PeakingWidth),
+    Lowshelf(ShelfSteepness),
+    Highshelf(ShelfSteepness),
     HighshelfFO {
         freq: f32,
         gain: f32,
@@ -115,14 +709,12 @@ pub enum BiquadParameters {
     HighpassFO {
         freq: f32,
     },
-    // for future use
-    #[allow(dead_code)]
     LowpassFO {
         freq: f32,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum PeakingWidth {
     Q {
@@ -139,85 +731,541 @@ pub enum PeakingWidth {
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ShelfSteepness {
+    Q { freq: f32, q: f32, gain: f32 },
+    Slope { freq: f32, slope: f32, gain: f32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GainParameters {
     pub gain: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<GainScale>,
+    #[serde(default)]
     pub inverted: bool,
+    #[serde(default)]
     pub mute: bool,
 }
 impl GainParameters {
+    fn linear_gain(&self) -> f32 {
+        let gain = match self.scale {
+            Some(GainScale::Linear) => self.gain,
+            _ => 10f32.powf(self.gain / 20.0),
+        };
+        match (self.mute, self.inverted) {
+            (true, _) => 0.0,
+            (false, true) => -gain,
+            (false, false) => gain,
+        }
+    }
     fn new(gain: f32) -> Self {
         GainParameters {
             gain,
+            scale: None,
             inverted: false,
             mute: false,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DelayParameters {
+    pub delay: f32,
+    pub unit: DelayUnit,
+    // since version 2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subsample: Option<bool>,
+}
+impl DelayParameters {
+    // samples depend on the sample rate
+    pub fn milliseconds(&self) -> Option<f32> {
+        match self.unit {
+            DelayUnit::Ms => Some(self.delay),
+            DelayUnit::Mm => Some(self.delay / SPEED_OF_SOUND),
+            DelayUnit::Samples => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DelayUnit {
+    #[serde(rename = "ms")]
+    Ms,
+    #[serde(rename = "samples")]
+    Samples,
+    // since version 2
+    #[serde(rename = "mm")]
+    Mm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GainScale {
+    #[serde(rename = "dB")]
+    Db,
+    #[serde(rename = "linear")]
+    Linear,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
 enum PipelineStep {
-    Mixer { name: String },
-    Filter { channel: usize, names: Vec<String> },
+    Mixer {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bypassed: Option<bool>,
+    },
+    Filter {
+        // a single channel up to version 2, a list of channels since version 3
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channels: Option<Vec<usize>>,
+        names: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bypassed: Option<bool>,
+    },
+    // e.g. processors of patched configurations
+    #[serde(untagged, skip_deserializing)]
+    Other(serde_yaml::Value),
 }
 
-#[derive(Debug)]
-pub enum DevicesFile {
-    Default,
-    Custom(String),
+fn deserialize_filters<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Filter>, D::Error> {
+    let filters = BTreeMap::<String, serde_yaml::Value>::deserialize(deserializer)?;
+    Ok(filters
+        .into_iter()
+        .map(|(name, filter)| {
+            let filter = serde_yaml::from_value(filter.clone()).unwrap_or(Filter::Other(filter));
+            (name, filter)
+        })
+        .collect())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Crossfeed {
-    None,
-    PowChuMoy,
-    Mpm,
-    Natural,
+fn deserialize_pipeline<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PipelineStep>, D::Error> {
+    let pipeline = Vec::<serde_yaml::Value>::deserialize(deserializer)?;
+    Ok(pipeline
+        .into_iter()
+        .map(|step| serde_yaml::from_value(step.clone()).unwrap_or(PipelineStep::Other(step)))
+        .collect())
 }
 
-impl fmt::Display for Crossfeed {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
+#[derive(Debug)]
+pub enum Correction {
+    Parametric(CorrectionFilterSet),
+    Graphic(GraphicEq, FirSettings),
 }
 
 pub fn build_configuration(
-    eq_data: CorrectionFilterSet,
+    correction: Correction,
     crossfeed: &Crossfeed,
+    headphone_name: &str,
+    devices: &DevicesFile,
+    version: CamillaDspVersion,
 ) -> Result<Configuration> {
     let mut configuration = Configuration::new();
+    configuration.devices = Some(load_devices(devices, version)?);
+    configuration.title = Some(format!("{} AutoEq", headphone_name));
+    configuration.description = Some(format!(
+        "AutoEq correction for {} with {} crossfeed, created by autoeq2camilladsp",
+        headphone_name, crossfeed
+    ));
     build_crossfeed(&mut configuration, crossfeed)?;
-    add_correction_eq_filtes(&mut configuration, eq_data);
+    let position = configuration.pipeline.len();
+    add_correction(&mut configuration, correction, headphone_name, position)?;
+    // only the title and description written above can get lost
+    configuration.set_version(version)?;
     Ok(configuration)
 }
 
-fn build_crossfeed(configuration: &mut Configuration, crossfeed: &Crossfeed) -> Result<()> {
-    match crossfeed {
-        Crossfeed::None => (),
-        Crossfeed::PowChuMoy => {
-            add_crossfeed_config(configuration, POWCHUMOY)?;
+#[derive(Debug, Clone, Copy)]
+pub enum PipelinePosition {
+    Start,
+    End,
+    Index(usize),
+}
+impl FromStr for PipelinePosition {
+    type Err = String;
+
+    fn from_str(position: &str) -> Result<Self, Self::Err> {
+        match position.to_lowercase().as_str() {
+            "start" => Ok(PipelinePosition::Start),
+            "end" => Ok(PipelinePosition::End),
+            index => index.parse().map(PipelinePosition::Index).map_err(|_| {
+                format!(
+                    "'{}' is not a valid position, use start, end or the index of a pipeline step",
+                    position
+                )
+            }),
         }
-        Crossfeed::Mpm => {
-            add_crossfeed_config(configuration, MPM)?;
+    }
+}
+
+// filters created by this tool, replaced when a configuration gets patched
+fn is_correction_filter(name: &str) -> bool {
+    name == "01_Preamp_Gain"
+        || name == "Correction_Eq_Fir"
+        || name.starts_with("Correction_Eq_Band_")
+}
+
+pub fn read_configuration(path: &Path) -> Result<Configuration> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Could not read configuration {}.", path.display()))?;
+    let invalid = || {
+        InputError(format!(
+            "{} is not a valid CamillaDSP configuration.",
+            path.display()
+        ))
+    };
+    let content: serde_yaml::Value = serde_yaml::from_str(&content).with_context(invalid)?;
+    let mut configuration: Configuration =
+        serde_yaml::from_value(content.clone()).with_context(invalid)?;
+    configuration.version = detect_version(&content);
+    configuration.directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    Ok(configuration)
+}
+
+// the oldest version a configuration can be for, files without anything specific to a
+// version are read by versions 1 and 2 alike
+fn detect_version(content: &serde_yaml::Value) -> CamillaDspVersion {
+    let has = |value: &serde_yaml::Value, key: &str| value.get(key).is_some();
+    let values = |key: &str| -> Vec<&serde_yaml::Value> {
+        match content.get(key) {
+            Some(serde_yaml::Value::Mapping(mapping)) => mapping.values().collect(),
+            Some(serde_yaml::Value::Sequence(sequence)) => sequence.iter().collect(),
+            _ => Vec::new(),
         }
-        Crossfeed::Natural => {
-            add_crossfeed_config(configuration, NATURAL)?;
+    };
+    let devices: Vec<&serde_yaml::Value> = content
+        .get("devices")
+        .into_iter()
+        .flat_map(|devices| [devices.get("capture"), devices.get("playback")])
+        .flatten()
+        .collect();
+    // the sample formats of version 3 are named like in ALSA, e.g. S16_LE
+    let formats: Vec<&str> = devices
+        .iter()
+        .copied()
+        .chain(
+            values("filters")
+                .into_iter()
+                .filter_map(|filter| filter.get("parameters")),
+        )
+        .filter_map(|value| value.get("format").and_then(|format| format.as_str()))
+        .collect();
+    let pipeline = values("pipeline");
+
+    let version_3 = pipeline.iter().any(|step| has(step, "channels"))
+        || values("mixers").iter().any(|mixer| has(mixer, "labels"))
+        || values("filters")
+            .iter()
+            .any(|filter| has(filter, "description"))
+        || formats.iter().any(|format| format.contains('_'));
+    let version_2 = has(content, "title")
+        || has(content, "description")
+        || has(content, "processors")
+        || content
+            .get("devices")
+            .is_some_and(|devices| has(devices, "resampler"))
+        || devices.iter().any(|device| {
+            device.get("type").and_then(|backend| backend.as_str()) == Some("RawFile")
+        })
+        || values("mixers")
+            .iter()
+            .any(|mixer| has(mixer, "description"))
+        || pipeline.iter().any(|step| {
+            has(step, "description")
+                || has(step, "bypassed")
+                || step.get("type").and_then(|kind| kind.as_str()) == Some("Processor")
+        })
+        || values("filters").iter().any(|filter| {
+            filter.get("parameters").is_some_and(|parameters| {
+                has(parameters, "scale")
+                    || has(parameters, "subsample")
+                    || parameters.get("unit").and_then(|unit| unit.as_str()) == Some("mm")
+            })
+        });
+    let version_1 = content
+        .get("devices")
+        .is_some_and(|devices| has(devices, "enable_resampling") || has(devices, "resampler_type"))
+        || pipeline.iter().any(|step| has(step, "channel"))
+        || !formats.is_empty();
+
+    match (version_3, version_2, version_1) {
+        (true, _, _) => CamillaDspVersion::V3,
+        (false, true, _) => CamillaDspVersion::V2,
+        (false, false, true) => CamillaDspVersion::V1,
+        // nothing to go by, so the correction is added for the newest version
+        (false, false, false) => CamillaDspVersion::default(),
+    }
+}
+
+// replaces the correction in an existing configuration and keeps everything else
+pub fn patch_configuration(
+    path: &Path,
+    correction: Correction,
+    headphone_name: &str,
+    position: Option<PipelinePosition>,
+    version: Option<CamillaDspVersion>,
+    warnings: &Warnings,
+) -> Result<Configuration> {
+    let mut configuration = read_configuration(path)?;
+    // the file keeps its version unless another one is asked for
+    let version = version.unwrap_or(configuration.version);
+
+    // by default the new correction takes the place of the old one
+    let previous = configuration.remove_correction();
+    let steps = configuration.pipeline.len();
+    let position = match position {
+        None => previous.unwrap_or(steps),
+        Some(PipelinePosition::Start) => 0,
+        Some(PipelinePosition::End) => steps,
+        Some(PipelinePosition::Index(index)) if index <= steps => index,
+        Some(PipelinePosition::Index(index)) => {
+            return Err(InputError(format!(
+                "The position {} is outside of the pipeline with {} steps.",
+                index, steps
+            ))
+            .into())
         }
+    };
+    add_correction(&mut configuration, correction, headphone_name, position)?;
+
+    if let Some(devices) = configuration.devices.as_mut() {
+        devices.set_version(version, &path.display().to_string())?;
+    }
+    for warning in configuration.set_version(version)? {
+        warnings.push(warning);
+    }
+    Ok(configuration)
+}
+
+fn add_correction(
+    configuration: &mut Configuration,
+    correction: Correction,
+    headphone_name: &str,
+    position: usize,
+) -> Result<()> {
+    match correction {
+        Correction::Parametric(eq_data) => {
+            add_correction_eq_filtes(configuration, eq_data, position)
+        }
+        Correction::Graphic(graphic_eq, fir) => {
+            add_correction_fir_filter(configuration, graphic_eq, &fir, headphone_name, position)?
+        }
+    }
+    Ok(())
+}
+
+// the crossfeed as pipeline stages for the export to other EQ software
+pub fn crossfeed_stages(crossfeed: &Crossfeed) -> Result<Vec<Stage>> {
+    let mut configuration = Configuration::new();
+    build_crossfeed(&mut configuration, crossfeed)?;
+    configuration.stages()
+}
+
+fn build_crossfeed(configuration: &mut Configuration, crossfeed: &Crossfeed) -> Result<()> {
+    match crossfeed {
+        Crossfeed::None => (),
+        Crossfeed::Preset(preset) => add_crossfeed_config(configuration, &preset.configuration),
+        Crossfeed::Custom { fcut, feed } => add_custom_crossfeed(configuration, *fcut, *feed),
+        Crossfeed::Hrir(hrir) => add_hrir_crossfeed(configuration, hrir)?,
     }
     Ok(())
 }
 
-fn add_crossfeed_config(configuration: &mut Configuration, config_bytes: &[u8]) -> Result<()> {
-    let mut partial_configuration: Configuration = serde_yaml::from_slice(config_bytes)
-        .context("Partial configuration could not be serialized.")?;
-    configuration.add_mixers(partial_configuration.mixers);
-    configuration.add_filters(partial_configuration.filters);
-    configuration.add_pipeline_steps(&mut partial_configuration.pipeline);
+// the filters of bs2b: the cross path is a first order lowpass at fcut, the direct path
+// a first order highshelf which lowers the bass by the same amount the cross path adds
+fn add_custom_crossfeed(configuration: &mut Configuration, fcut: f32, feed: f32) {
+    let gain_low = -5.0 / 6.0 * feed - 3.0;
+    let gain_high = feed / 6.0 - 3.0;
+    let highboost = 1.0 - 10f32.powf(gain_high / 20.0);
+    let fcut_high = fcut * 2f32.powf((gain_low - 20.0 * highboost.log10()) / 12.0);
+
+    configuration.add_filter(
+        "XF_Cross_Lowpass".to_string(),
+        Filter::Biquad {
+            parameters: BiquadParameters::LowpassFO { freq: fcut },
+        },
+    );
+    // bs2b gives the pole, CamillaDSP the center between pole and zero
+    configuration.add_filter(
+        "XF_Direct_Highshelf".to_string(),
+        Filter::Biquad {
+            parameters: BiquadParameters::HighshelfFO {
+                freq: fcut_high * 10f32.powf(gain_high / 40.0),
+                gain: -gain_high,
+            },
+        },
+    );
+    add_crossfeed_routing(
+        configuration,
+        gain_low,
+        gain_high,
+        [
+            "XF_Cross_Lowpass",
+            "XF_Direct_Highshelf",
+            "XF_Direct_Highshelf",
+            "XF_Cross_Lowpass",
+        ],
+    );
+}
+
+// the impulses are used where they are unless they need to be resampled for the devices
+fn add_hrir_crossfeed(configuration: &mut Configuration, hrir: &Hrir) -> Result<()> {
+    let samplerate = match &configuration.devices {
+        Some(devices) => devices.samplerate,
+        None => {
+            return Err(InputError(
+                "The HRIR crossfeed can only be used with CamillaDSP.".to_string(),
+            )
+            .into())
+        }
+    };
+    let names = [
+        "XF_HRIR_Left_Cross",
+        "XF_HRIR_Left_Direct",
+        "XF_HRIR_Right_Direct",
+        "XF_HRIR_Right_Cross",
+    ];
+    for (name, impulse) in names.iter().zip(hrir.impulses.iter()) {
+        let parameters = match hrir.samplerate == samplerate {
+            true => ConvParameters::Wav {
+                filename: impulse.path.display().to_string(),
+                channel: impulse.channel,
+            },
+            false => {
+                let filename = format!(
+                    "{}-{}-{}.wav",
+                    impulse
+                        .path
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().replace(' ', "_"))
+                        .unwrap_or_default(),
+                    impulse.channel,
+                    samplerate
+                );
+                // the pair of mono files uses every impulse twice
+                if !configuration
+                    .impulse_files
+                    .iter()
+                    .any(|impulse_file| impulse_file.filename == filename)
+                {
+                    configuration.impulse_files.push(ImpulseFile {
+                        filename: filename.clone(),
+                        samples: resample(&impulse.samples, hrir.samplerate, samplerate),
+                        samplerate,
+                        format: ImpulseFormat::Wav,
+                    });
+                }
+                ConvParameters::Wav {
+                    filename,
+                    channel: 0,
+                }
+            }
+        };
+        configuration.add_filter(name.to_string(), Filter::Conv { parameters });
+    }
+    add_crossfeed_routing(configuration, 0.0, 0.0, names);
     Ok(())
 }
 
-fn add_correction_eq_filtes(configuration: &mut Configuration, data: CorrectionFilterSet) {
+// splits both channels into a cross and a direct path, filters every path and sums them up
+fn add_crossfeed_routing(
+    configuration: &mut Configuration,
+    gain_cross: f32,
+    gain_direct: f32,
+    filter_names: [&str; 4],
+) {
+    let source = |channel: usize, gain: f32| MixerSource {
+        channel,
+        gain,
+        scale: None,
+        inverted: false,
+        mute: false,
+    };
+    let mixer =
+        |description: &str, channels: MixerChannels, sources: Vec<Vec<MixerSource>>| Mixer {
+            description: Some(description.to_string()),
+            channels,
+            mapping: sources
+                .into_iter()
+                .enumerate()
+                .map(|(dest, sources)| MixerMapping {
+                    dest,
+                    sources,
+                    mute: false,
+                })
+                .collect(),
+            options: BTreeMap::new(),
+        };
+    configuration.add_mixer(
+        "XF_IN".to_string(),
+        mixer(
+            "Splits both channels into direct and cross paths",
+            MixerChannels { r#in: 2, out: 4 },
+            vec![
+                vec![source(0, gain_cross)],
+                vec![source(0, gain_direct)],
+                vec![source(1, gain_direct)],
+                vec![source(1, gain_cross)],
+            ],
+        ),
+    );
+    configuration.add_mixer(
+        "XF_OUT".to_string(),
+        mixer(
+            "Sums up the direct and cross paths",
+            MixerChannels { r#in: 4, out: 2 },
+            vec![
+                vec![source(1, 0.0), source(3, 0.0)],
+                vec![source(2, 0.0), source(0, 0.0)],
+            ],
+        ),
+    );
+
+    let filter_step = |channel: usize, name: &str| PipelineStep::Filter {
+        channel: Some(channel),
+        channels: None,
+        names: vec![name.to_string()],
+        description: None,
+        bypassed: None,
+    };
+    let mixer_step = |name: &str| PipelineStep::Mixer {
+        name: name.to_string(),
+        description: None,
+        bypassed: None,
+    };
+    let mut steps = vec![mixer_step("XF_IN")];
+    steps.extend(
+        filter_names
+            .iter()
+            .enumerate()
+            .map(|(channel, name)| filter_step(channel, name)),
+    );
+    steps.push(mixer_step("XF_OUT"));
+    configuration.add_pipeline_steps(&mut steps);
+}
+
+fn add_crossfeed_config(configuration: &mut Configuration, preset: &Configuration) {
+    configuration.add_mixers(preset.mixers.clone());
+    configuration.add_filters(preset.filters.clone());
+    configuration.add_pipeline_steps(&mut preset.pipeline.clone());
+}
+
+fn add_correction_eq_filtes(
+    configuration: &mut Configuration,
+    data: CorrectionFilterSet,
+    position: usize,
+) {
     let mut correction_eq_filters = BTreeMap::new();
 
     correction_eq_filters.insert(
@@ -232,70 +1280,293 @@ fn add_correction_eq_filtes(configuration: &mut Configuration, data: CorrectionF
         correction_eq_filters.insert(name, Filter::Biquad { parameters: band });
     });
 
-    let filter_names: Vec<String> = correction_eq_filters.keys().cloned().collect();
+    add_correction_pipeline_steps(configuration, correction_eq_filters, position);
+}
 
-    configuration.add_pipeline_step(PipelineStep::Filter {
-        channel: 0,
-        names: filter_names.clone(),
-    });
-    configuration.add_pipeline_step(PipelineStep::Filter {
-        channel: 1,
-        names: filter_names,
-    });
+fn add_correction_fir_filter(
+    configuration: &mut Configuration,
+    graphic_eq: GraphicEq,
+    fir: &FirSettings,
+    headphone_name: &str,
+    position: usize,
+) -> Result<()> {
+    // CamillaDSP plays the impulse at the rate of the devices, whatever it was designed for
+    let samplerate = match (fir.samplerate, &configuration.devices) {
+        (Some(samplerate), Some(devices)) if samplerate != devices.samplerate => {
+            return Err(InputError(format!(
+                "The FIR filter sample rate {} does not match the sample rate {} of the devices.",
+                samplerate, devices.samplerate
+            ))
+            .into())
+        }
+        (Some(samplerate), _) => samplerate,
+        (None, Some(devices)) => devices.samplerate,
+        (None, None) => DEFAULT_FIR_SAMPLERATE,
+    };
+    let impulse_file = ImpulseFile {
+        filename: create_impulse_filename(headphone_name, fir, samplerate),
+        samples: impulse_response(&graphic_eq, fir, samplerate)?,
+        samplerate,
+        format: fir.format,
+    };
+    let parameters = match fir.format {
+        ImpulseFormat::Wav => ConvParameters::Wav {
+            filename: impulse_file.filename.clone(),
+            channel: 0,
+        },
+        ImpulseFormat::Raw => ConvParameters::Raw {
+            filename: impulse_file.filename.clone(),
+            format: RawFormat::Float32Le,
+        },
+    };
+    configuration.impulse_files.push(impulse_file);
+
+    let mut correction_fir_filters = BTreeMap::new();
+    correction_fir_filters.insert(
+        "01_Preamp_Gain".to_string(),
+        Filter::Gain {
+            parameters: GainParameters::new(graphic_eq.gain),
+        },
+    );
+    correction_fir_filters.insert("Correction_Eq_Fir".to_string(), Filter::Conv { parameters });
+
+    add_correction_pipeline_steps(configuration, correction_fir_filters, position);
+    Ok(())
+}
+
+fn add_correction_pipeline_steps(
+    configuration: &mut Configuration,
+    correction_filters: BTreeMap<String, Filter>,
+    position: usize,
+) {
+    let filter_names: Vec<String> = correction_filters.keys().cloned().collect();
+
+    configuration.insert_pipeline_step(
+        position,
+        PipelineStep::Filter {
+            channel: None,
+            channels: Some(vec![0, 1]),
+            names: filter_names,
+            description: None,
+            bypassed: None,
+        },
+    );
+
+    configuration.add_filters(correction_filters);
+}
 
-    configuration.add_filters(correction_eq_filters);
+#[derive(Debug)]
+pub struct WrittenFiles {
+    pub config: PathBuf,
+    pub impulse_files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct OutputSettings {
+    // file, directory or '-' for stdout, the current directory by default
+    pub path: Option<PathBuf>,
+    // template for the file name with {headphone}, {source}, {crossfeed} and {samplerate}
+    pub filename: Option<String>,
+    pub force: bool,
+}
+impl OutputSettings {
+    fn is_stdout(&self) -> bool {
+        self.path.as_deref() == Some(Path::new("-"))
+    }
 }
 
 pub fn write_yml_file(
-    configuration: Configuration,
+    configuration: &Configuration,
     headphone_name: &str,
-    devices: &DevicesFile,
+    source: Option<&str>,
     crossfeed: &Crossfeed,
-) -> Result<()> {
-    let devices_config = get_devices(devices)?;
-    let mut config_file = create_config_file(headphone_name, crossfeed)?;
+    output: &OutputSettings,
+) -> Result<WrittenFiles> {
+    let filename = match &output.filename {
+        Some(template) => render_filename(
+            template,
+            headphone_name,
+            source,
+            crossfeed,
+            configuration
+                .devices
+                .as_ref()
+                .map(|devices| devices.samplerate),
+        ),
+        None => create_filename(headphone_name, crossfeed, OutputFormat::CamillaDsp),
+    };
+    let path = create_config_path(output, filename)?;
+
+    let mut config_file = create_config_file()?;
     write_lines_to_file(
         &mut config_file,
         include_str!("data/header.yml").to_string(),
     )?;
-    write_lines_to_file(&mut config_file, devices_config)?;
-    serialize_and_write_yaml(&mut config_file, &configuration)?;
+    serialize_and_write_yaml(&mut config_file, configuration)?;
+
+    // impulse files are referenced relative to the configuration file
+    let directory = match output.is_stdout() {
+        true => Path::new("."),
+        false => path.parent().unwrap_or(Path::new(".")),
+    };
+    let impulse_files: Vec<(PathBuf, Vec<u8>)> = configuration
+        .impulse_files
+        .iter()
+        .map(|impulse_file| {
+            (
+                directory.join(&impulse_file.filename),
+                impulse_file.to_bytes(),
+            )
+        })
+        .collect();
+
+    // check everything first so nothing gets written when one of the files exists
+    if !output.force {
+        let existing = impulse_files
+            .iter()
+            .map(|(path, _)| path)
+            .chain((!output.is_stdout()).then_some(&path))
+            .find(|path| path.exists());
+        if let Some(path) = existing {
+            return Err(FileExistsError { path: path.clone() }.into());
+        }
+    }
+    for (path, bytes) in impulse_files.iter() {
+        write_file_atomically(path, bytes)?;
+    }
+    match output.is_stdout() {
+        true => io::stdout()
+            .write_all(&config_file)
+            .context("Could not write configuration to stdout.")?,
+        false => write_file_atomically(&path, &config_file)?,
+    }
+
+    Ok(WrittenFiles {
+        config: path,
+        impulse_files: impulse_files.into_iter().map(|(path, _)| path).collect(),
+    })
+}
+
+// configurations of other EQ software only consist of a single file
+pub fn write_export_file(
+    content: &str,
+    headphone_name: &str,
+    source: Option<&str>,
+    crossfeed: &Crossfeed,
+    format: OutputFormat,
+    output: &OutputSettings,
+) -> Result<PathBuf> {
+    let filename = match &output.filename {
+        Some(template) => render_filename(template, headphone_name, source, crossfeed, None),
+        None => create_filename(headphone_name, crossfeed, format),
+    };
+    let path = create_config_path(output, filename)?;
+    if output.is_stdout() {
+        io::stdout()
+            .write_all(content.as_bytes())
+            .context("Could not write configuration to stdout.")?;
+        return Ok(path);
+    }
+    if !output.force && path.exists() {
+        return Err(FileExistsError { path }.into());
+    }
+    write_file_atomically(&path, content.as_bytes())?;
+    Ok(path)
+}
+
+// a temporary file gets renamed so CamillaDSP never reads a half written file
+fn write_file_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let filename = path
+        .file_name()
+        .with_context(|| format!("{} is not a valid file name.", path.display()))?;
+    let temporary_path = path.with_file_name(format!(".{}.tmp", filename.to_string_lossy()));
+    fs::write(&temporary_path, bytes)
+        .with_context(|| format!("Could not write {}.", path.display()))?;
+    if let Err(error) = fs::rename(&temporary_path, path) {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(error).with_context(|| format!("Could not write {}.", path.display()));
+    }
     Ok(())
 }
 
-fn get_devices(devices: &DevicesFile) -> Result<String> {
-    let devices_config = match devices {
-        DevicesFile::Default => include_str!("data/default_devices.yml").to_string(),
-        DevicesFile::Custom(path) => {
-            let mut file =
-                File::open(path).context("Could not open file with custom devices section.")?;
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer)
-                .context("Could not read file with custom devices section.")?;
-            buffer
+fn create_config_path(output: &OutputSettings, filename: String) -> Result<PathBuf> {
+    let path = match &output.path {
+        Some(path) if output.is_stdout() => path.clone(),
+        Some(path) if path.is_dir() => path.join(filename),
+        Some(path) if path.to_string_lossy().ends_with(std::path::is_separator) => {
+            fs::create_dir_all(path)
+                .with_context(|| format!("Could not create directory {}.", path.display()))?;
+            path.join(filename)
         }
+        Some(path) => path.clone(),
+        None => PathBuf::from(filename),
     };
-    Ok(devices_config)
+    Ok(path)
+}
+
+fn render_filename(
+    template: &str,
+    headphone_name: &str,
+    source: Option<&str>,
+    crossfeed: &Crossfeed,
+    samplerate: Option<usize>,
+) -> String {
+    template
+        .replace("{headphone}", &clean_filename(headphone_name))
+        .replace("{source}", &clean_filename(source.unwrap_or("local")))
+        .replace("{crossfeed}", &clean_filename(&crossfeed.to_string()))
+        .replace(
+            "{samplerate}",
+            &samplerate
+                .map(|samplerate| samplerate.to_string())
+                .unwrap_or_default(),
+        )
 }
 
-fn create_config_file(headphone_name: &str, crossfeed: &Crossfeed) -> Result<File> {
-    let filename = create_filename(headphone_name, crossfeed);
-    let mut config_file = File::create(filename).context("Could not create configuration file.")?;
+fn create_config_file() -> Result<Vec<u8>> {
+    let mut config_file = Vec::new();
     writeln!(config_file, "---").context("Could not write to configuration file.")?;
     Ok(config_file)
 }
 
-fn create_filename(headphone_name: &str, crossfeed: &Crossfeed) -> String {
-    let crossfeed: &str = match crossfeed {
-        Crossfeed::None => "",
-        Crossfeed::PowChuMoy => "-ChuMoy",
-        Crossfeed::Mpm => "-MPM",
-        Crossfeed::Natural => "-Natural",
+// names of headphones and user presets must not create subdirectories
+fn clean_filename(value: &str) -> String {
+    value.replace(['/', '\\'], "-").replace(' ', "_")
+}
+
+fn create_filename(headphone_name: &str, crossfeed: &Crossfeed, format: OutputFormat) -> String {
+    let crossfeed = match crossfeed {
+        Crossfeed::None => String::new(),
+        crossfeed => format!("-{}", clean_filename(&crossfeed.filename())),
+    };
+    let suffix = match format {
+        OutputFormat::CamillaDsp => String::new(),
+        format => format!("-{}", format),
+    };
+    format!(
+        "{}-EQ{}{}.{}",
+        clean_filename(headphone_name),
+        crossfeed,
+        suffix,
+        format.extension()
+    )
+}
+
+fn create_impulse_filename(headphone_name: &str, fir: &FirSettings, samplerate: usize) -> String {
+    let extension = match fir.format {
+        ImpulseFormat::Wav => "wav",
+        ImpulseFormat::Raw => "raw",
     };
-    format!("{}-EQ{}.yml", headphone_name.replace(' ', "_"), crossfeed)
+    format!(
+        "{}-EQ-FIR-{}-{}.{}",
+        clean_filename(headphone_name),
+        fir.phase,
+        samplerate,
+        extension
+    )
 }
 
-fn write_lines_to_file(file: &mut File, data: String) -> Result<()> {
+fn write_lines_to_file(file: &mut impl Write, data: String) -> Result<()> {
     for line in data.lines() {
         if line != "---" {
             writeln!(file, "{}", line)
@@ -305,7 +1576,7 @@ fn write_lines_to_file(file: &mut File, data: String) -> Result<()> {
     Ok(())
 }
 
-fn serialize_and_write_yaml(file: &mut File, configuration: &Configuration) -> Result<()> {
+fn serialize_and_write_yaml(file: &mut impl Write, configuration: &Configuration) -> Result<()> {
     let serialized_yaml = serde_yaml::to_string(configuration)
         .context("The ParametricEq filter settings could not be serialized to yaml.")?
         .into_bytes();
@@ -313,3 +1584,197 @@ fn serialize_and_write_yaml(file: &mut File, configuration: &Configuration) -> R
         .context("Unaible to write serialized config to file.")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scraping::GraphicEqPoint;
+
+    fn configuration(content: &str) -> Configuration {
+        let content: serde_yaml::Value = serde_yaml::from_str(content).unwrap();
+        let mut configuration: Configuration = serde_yaml::from_value(content.clone()).unwrap();
+        configuration.version = detect_version(&content);
+        configuration
+    }
+
+    const VERSION_3: &str = "
+mixers:
+  Mono:
+    labels: [M]
+    channels: {in: 2, out: 1}
+    mapping:
+      - dest: 0
+        sources: [{channel: 0, gain: -6}, {channel: 1, gain: -6}]
+processors:
+  Compressor:
+    type: Compressor
+    parameters: {channels: 1}
+pipeline:
+  - type: Mixer
+    name: Mono
+  - type: Processor
+    name: Compressor
+";
+
+    #[test]
+    fn versions_are_detected_by_their_settings() {
+        let detect = |content: &str| configuration(content).version;
+        assert_eq!(
+            detect("pipeline:\n  - {type: Filter, channel: 0, names: [A]}"),
+            CamillaDspVersion::V1
+        );
+        assert_eq!(
+            detect("title: Mine\npipeline:\n  - {type: Filter, channel: 0, names: [A]}"),
+            CamillaDspVersion::V2
+        );
+        assert_eq!(
+            detect("pipeline:\n  - {type: Filter, channels: [0], names: [A]}"),
+            CamillaDspVersion::V3
+        );
+        assert_eq!(detect(VERSION_3), CamillaDspVersion::V3);
+        assert_eq!(detect("filters: {}"), CamillaDspVersion::V3);
+    }
+
+    #[test]
+    fn removed_settings_are_reported() {
+        let mut configuration = configuration(VERSION_3);
+        let warnings = configuration.set_version(CamillaDspVersion::V2).unwrap();
+        assert!(configuration.mixers["Mono"].options.is_empty());
+        assert_eq!(
+            warnings,
+            [
+                "Removed for CamillaDSP V2: labels of the mixer Mono.",
+                "1 pipeline steps are not converted from CamillaDSP V3 to V2 and may need changes."
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_kept_without_conversion() {
+        let mut configuration = configuration(VERSION_3);
+        assert!(configuration
+            .set_version(CamillaDspVersion::V3)
+            .unwrap()
+            .is_empty());
+        assert!(configuration.mixers["Mono"].options.contains_key("labels"));
+    }
+
+    #[test]
+    fn processors_can_not_be_converted_to_version_1() {
+        let mut configuration = configuration(VERSION_3);
+        assert!(configuration.set_version(CamillaDspVersion::V1).is_err());
+    }
+
+    #[test]
+    fn linear_gains_are_converted_for_version_1() {
+        let mut configuration = configuration(
+            "filters:\n  Half:\n    type: Gain\n    parameters: {gain: -0.5, scale: linear}",
+        );
+        configuration.set_version(CamillaDspVersion::V1).unwrap();
+        let Some(Filter::Gain { parameters }) = configuration.filters.get("Half") else {
+            panic!("the filter is not a gain");
+        };
+        assert!((parameters.gain + 6.0206).abs() < 0.001);
+        assert!(parameters.inverted);
+        assert!(parameters.scale.is_none());
+    }
+
+    // every test writes its own file as they run in parallel
+    fn devices_48k(test: &str) -> DevicesFile {
+        let path = std::env::temp_dir().join(format!("{}-{}.yml", test, std::process::id()));
+        fs::write(
+            &path,
+            include_str!("data/default_devices.yml").replace("44100", "48000"),
+        )
+        .unwrap();
+        DevicesFile::Custom(path.display().to_string())
+    }
+
+    fn graphic_correction(samplerate: Option<usize>) -> Correction {
+        let point = |freq, gain| GraphicEqPoint { freq, gain };
+        let graphic_eq = GraphicEq {
+            gain: -6.0,
+            points: vec![point(20.0, 0.0), point(1000.0, 6.0), point(20000.0, 0.0)],
+        };
+        let fir = FirSettings {
+            samplerate,
+            ..FirSettings::default()
+        };
+        Correction::Graphic(graphic_eq, fir)
+    }
+
+    #[test]
+    fn fir_filters_are_designed_for_the_sample_rate_of_the_devices() {
+        let configuration = build_configuration(
+            graphic_correction(None),
+            &Crossfeed::None,
+            "Test",
+            &devices_48k("fir-devices-rate"),
+            CamillaDspVersion::V3,
+        )
+        .unwrap();
+        let impulse_file = &configuration.impulse_files[0];
+        assert_eq!(impulse_file.samplerate, 48000);
+        assert!(impulse_file.filename.ends_with("-48000.wav"));
+        // the peak of the graphic EQ stays at 1 kHz
+        let response = configuration
+            .frequency_response(&[900.0, 1000.0, 1100.0])
+            .unwrap();
+        let gains: Vec<f64> = response.transfer[0][0]
+            .iter()
+            .copied()
+            .map(gain_db)
+            .collect();
+        assert!(gains[1].abs() < 0.2, "{:?}", gains);
+        assert!(gains[1] > gains[0] && gains[1] > gains[2], "{:?}", gains);
+    }
+
+    #[test]
+    fn fir_filters_for_another_sample_rate_are_rejected() {
+        let error = build_configuration(
+            graphic_correction(Some(44100)),
+            &Crossfeed::None,
+            "Test",
+            &devices_48k("fir-other-rate"),
+            CamillaDspVersion::V3,
+        )
+        .unwrap_err();
+        assert!(error.downcast_ref::<InputError>().is_some());
+    }
+
+    #[test]
+    fn negative_linear_gains_are_kept_linear() {
+        let configuration = configuration(
+            "filters:\n  Invert:\n    type: Gain\n    parameters: {gain: -0.5, scale: linear}
+pipeline:\n  - type: Filter\n    channel: 0\n    names: [Invert]",
+        );
+        let stages = configuration.stages().unwrap();
+        let [Stage::Filter { filters, .. }] = &stages[..] else {
+            panic!("the pipeline is not a single filter stage");
+        };
+        assert!(matches!(filters[..], [StageFilter::Linear(gain)] if gain == -0.5));
+    }
+
+    #[test]
+    fn impulse_files_are_read_next_to_the_configuration() {
+        let directory =
+            std::env::temp_dir().join(format!("relative-impulse-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("impulse.raw"), 0.5f32.to_le_bytes()).unwrap();
+        let path = directory.join("config.yml");
+        fs::write(
+            &path,
+            "filters:\n  Impulse:\n    type: Conv
+    parameters: {type: Raw, filename: impulse.raw, format: F32_LE}",
+        )
+        .unwrap();
+        let configuration = read_configuration(&path).unwrap();
+        let Some(Filter::Conv { parameters }) = configuration.filters.get("Impulse") else {
+            panic!("the filter is not a convolution");
+        };
+        assert_eq!(
+            configuration.impulse_samples(parameters).unwrap(),
+            vec![0.5]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::configcreation::Configuration;
+use crate::fir::read_wav;
+use crate::report::{ConfigError, InputError, Warnings};
+
+static BUILT_IN_PRESETS: [&[u8]; 6] = [
+    include_bytes!("data/pow_chu_moy.yml"),
+    include_bytes!("data/mpm.yml"),
+    include_bytes!("data/natural.yml"),
+    include_bytes!("data/bauer.yml"),
+    include_bytes!("data/chu_moy_delay.yml"),
+    include_bytes!("data/meier.yml"),
+];
+
+// the same limits and defaults as bs2b
+pub const CROSSFEED_FCUT: RangeInclusive<f32> = 300.0..=2000.0;
+pub const CROSSFEED_FEED: RangeInclusive<f32> = 1.0..=15.0;
+pub const CROSSFEED_FCUT_DEFAULT: f32 = 700.0;
+pub const CROSSFEED_FEED_DEFAULT: f32 = 4.5;
+
+// a partial configuration with the mixers, filters and pipeline steps of the crossfeed
+#[derive(Debug, Deserialize)]
+pub struct CrossfeedPreset {
+    pub name: String,
+    pub description: String,
+    // appended to the default file name instead of the name
+    #[serde(default)]
+    filename: Option<String>,
+    #[serde(flatten)]
+    pub configuration: Configuration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum Crossfeed {
+    #[default]
+    None,
+    Preset(Arc<CrossfeedPreset>),
+    // bs2b style crossfeed with the cutoff frequency in Hz and the feed level in dB
+    Custom {
+        fcut: f32,
+        feed: f32,
+    },
+    // convolution with measured head related impulse responses
+    Hrir(Arc<Hrir>),
+}
+impl Crossfeed {
+    pub const CUSTOM: Crossfeed = Crossfeed::Custom {
+        fcut: CROSSFEED_FCUT_DEFAULT,
+        feed: CROSSFEED_FEED_DEFAULT,
+    };
+
+    // part of the default file name
+    pub fn filename(&self) -> String {
+        match self {
+            Crossfeed::None => String::new(),
+            Crossfeed::Preset(preset) => preset.filename.as_ref().unwrap_or(&preset.name).clone(),
+            Crossfeed::Hrir(hrir) => format!("HRIR-{}", hrir.name()),
+            crossfeed => crossfeed.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Crossfeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Crossfeed::None => write!(f, "None"),
+            Crossfeed::Preset(preset) => write!(f, "{}", preset.name),
+            Crossfeed::Custom { fcut, feed } => write!(f, "Custom-{}Hz-{}dB", fcut, feed),
+            Crossfeed::Hrir(_) => write!(f, "HRIR"),
+        }
+    }
+}
+
+// the crossfeed as given on the command line or in the JSON input, either a name or parameters
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum CrossfeedSelection {
+    Name(String),
+    Parameters(CrossfeedParameters),
+}
+impl Default for CrossfeedSelection {
+    fn default() -> Self {
+        CrossfeedSelection::Name("None".to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum CrossfeedParameters {
+    Custom { fcut: f32, feed: f32 },
+    Hrir(HrirFiles),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HrirFiles {
+    // mono responses of the left speaker, the right speaker mirrors them
+    Pair {
+        ipsilateral: PathBuf,
+        contralateral: PathBuf,
+    },
+    // left speaker to left and right ear, then right speaker to left and right ear
+    Speakers {
+        speakers: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct HrirImpulse {
+    pub path: PathBuf,
+    pub channel: usize,
+    pub samples: Vec<f32>,
+}
+
+// the impulses in the order of the crossfeed channels: left cross, left direct,
+// right direct and right cross
+#[derive(Debug)]
+pub struct Hrir {
+    pub samplerate: usize,
+    pub impulses: [HrirImpulse; 4],
+}
+impl Hrir {
+    pub fn load(files: &HrirFiles) -> Result<Self> {
+        let hrir = match files {
+            HrirFiles::Pair {
+                ipsilateral,
+                contralateral,
+            } => {
+                let ([ipsilateral], samplerate) = read_hrir(ipsilateral)?;
+                let ([contralateral], contralateral_samplerate) = read_hrir(contralateral)?;
+                if samplerate != contralateral_samplerate {
+                    return Err(InputError(
+                        "The ipsilateral and contralateral HRIR need the same sample rate."
+                            .to_string(),
+                    )
+                    .into());
+                }
+                Hrir {
+                    samplerate,
+                    impulses: [
+                        contralateral.clone(),
+                        ipsilateral.clone(),
+                        ipsilateral,
+                        contralateral,
+                    ],
+                }
+            }
+            HrirFiles::Speakers { speakers } => {
+                let ([left_left, left_right, right_left, right_right], samplerate) =
+                    read_hrir(speakers)?;
+                Hrir {
+                    samplerate,
+                    impulses: [left_right, left_left, right_right, right_left],
+                }
+            }
+        };
+        Ok(hrir)
+    }
+
+    // the name of the file with the direct path
+    fn name(&self) -> String {
+        self.impulses[1]
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().replace(' ', "_"))
+            .unwrap_or_default()
+    }
+}
+
+fn read_hrir<const N: usize>(path: &Path) -> Result<([HrirImpulse; N], usize)> {
+    let path = path
+        .canonicalize()
+        .with_context(|| InputError(format!("The HRIR file {} does not exist.", path.display())))?;
+    let bytes = fs::read(&path)
+        .with_context(|| InputError(format!("Could not read the HRIR file {}.", path.display())))?;
+    let wav = read_wav(&bytes)
+        .with_context(|| InputError(format!("{} is not a supported WAV file.", path.display())))?;
+    if wav.channels.len() != N || wav.channels[0].is_empty() {
+        return Err(InputError(format!(
+            "The HRIR file {} needs {} channel{} with samples.",
+            path.display(),
+            N,
+            if N == 1 { "" } else { "s" }
+        ))
+        .into());
+    }
+    let impulses: Vec<HrirImpulse> = wav
+        .channels
+        .into_iter()
+        .enumerate()
+        .map(|(channel, samples)| HrirImpulse {
+            path: path.clone(),
+            channel,
+            samples,
+        })
+        .collect();
+    Ok((impulses.try_into().unwrap(), wav.samplerate))
+}
+
+// the built-in presets followed by the ones of the user
+#[derive(Debug, Default)]
+pub struct CrossfeedPresets {
+    presets: Vec<Arc<CrossfeedPreset>>,
+}
+impl CrossfeedPresets {
+    // broken user presets are skipped with a warning so they do not stop everything else
+    pub fn load(directory: Option<PathBuf>, warnings: &Warnings) -> Result<Self> {
+        let mut presets = CrossfeedPresets::default();
+        for preset in BUILT_IN_PRESETS {
+            let preset: CrossfeedPreset = serde_yaml::from_slice(preset)
+                .context("Built-in crossfeed preset could not be serialized.")?;
+            preset.configuration.validate_crossfeed()?;
+            presets.presets.push(Arc::new(preset));
+        }
+
+        let directory = match directory {
+            Some(directory) if !directory.is_dir() => {
+                return Err(ConfigError(format!(
+                    "The crossfeed directory {} does not exist.",
+                    directory.display()
+                ))
+                .into())
+            }
+            Some(directory) => directory,
+            None => match dirs::config_dir() {
+                Some(dir) if dir.join("autoeq2camilladsp/crossfeed").is_dir() => {
+                    dir.join("autoeq2camilladsp/crossfeed")
+                }
+                _ => return Ok(presets),
+            },
+        };
+        let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+            .with_context(|| {
+                ConfigError(format!(
+                    "Could not read the crossfeed directory {}.",
+                    directory.display()
+                ))
+            })?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "yml" || extension == "yaml")
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            if let Err(error) = presets.add_user_preset(&path) {
+                warnings.push(format!(
+                    "The crossfeed preset {} was skipped: {:#}",
+                    path.display(),
+                    error
+                ));
+            }
+        }
+        Ok(presets)
+    }
+
+    fn add_user_preset(&mut self, path: &Path) -> Result<()> {
+        let preset = fs::read(path).context("the file could not be read")?;
+        let preset: CrossfeedPreset =
+            serde_yaml::from_slice(&preset).context("the file is not a valid preset")?;
+        if ["none", "custom", "hrir"].contains(&preset.name.to_lowercase().as_str())
+            || self.get(&preset.name).is_some()
+        {
+            return Err(anyhow::anyhow!("the name {} is already taken", preset.name));
+        }
+        preset.configuration.validate_crossfeed()?;
+        self.presets.push(Arc::new(preset));
+        Ok(())
+    }
+
+    pub fn presets(&self) -> &[Arc<CrossfeedPreset>] {
+        &self.presets
+    }
+
+    fn get(&self, name: &str) -> Option<&Arc<CrossfeedPreset>> {
+        self.presets
+            .iter()
+            .find(|preset| preset.name.eq_ignore_ascii_case(name))
+    }
+
+    // every name that can be selected, including None and Custom
+    pub fn names(&self) -> Vec<String> {
+        [Crossfeed::None.to_string()]
+            .into_iter()
+            .chain(self.presets.iter().map(|preset| preset.name.clone()))
+            .chain(["Custom".to_string()])
+            .collect()
+    }
+
+    pub fn select(&self, selection: &CrossfeedSelection) -> Result<Crossfeed> {
+        let crossfeed = match selection {
+            CrossfeedSelection::Name(name) if name.eq_ignore_ascii_case("none") => Crossfeed::None,
+            CrossfeedSelection::Name(name) if name.eq_ignore_ascii_case("custom") => {
+                Crossfeed::CUSTOM
+            }
+            CrossfeedSelection::Name(name) if name.eq_ignore_ascii_case("hrir") => {
+                return Err(InputError(
+                    "The HRIR crossfeed needs an ipsilateral and a contralateral file or a file with four channels.".to_string(),
+                )
+                .into())
+            }
+            CrossfeedSelection::Name(name) => match self.get(name) {
+                Some(preset) => Crossfeed::Preset(preset.clone()),
+                None => {
+                    return Err(InputError(format!(
+                        "'{}' is not a crossfeed preset, use one of {}.",
+                        name,
+                        self.names().join(", ")
+                    ))
+                    .into())
+                }
+            },
+            CrossfeedSelection::Parameters(CrossfeedParameters::Custom { fcut, feed }) => {
+                if !CROSSFEED_FCUT.contains(fcut) || !CROSSFEED_FEED.contains(feed) {
+                    return Err(InputError(format!(
+                        "The crossfeed needs a cutoff between {} and {} Hz and a feed between {} and {} dB.",
+                        CROSSFEED_FCUT.start(),
+                        CROSSFEED_FCUT.end(),
+                        CROSSFEED_FEED.start(),
+                        CROSSFEED_FEED.end()
+                    ))
+                    .into());
+                }
+                Crossfeed::Custom {
+                    fcut: *fcut,
+                    feed: *feed,
+                }
+            }
+            CrossfeedSelection::Parameters(CrossfeedParameters::Hrir(files)) => {
+                Crossfeed::Hrir(Arc::new(Hrir::load(files)?))
+            }
+        };
+        Ok(crossfeed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(json: &str) -> Crossfeed {
+        let presets = CrossfeedPresets::load(None, &Warnings::default()).unwrap();
+        let selection: CrossfeedSelection = serde_json::from_str(json).unwrap();
+        presets.select(&selection).unwrap()
+    }
+
+    #[test]
+    fn custom_name_selects_the_defaults() {
+        match select(r#""Custom""#) {
+            Crossfeed::Custom { fcut, feed } => {
+                assert_eq!(fcut, CROSSFEED_FCUT_DEFAULT);
+                assert_eq!(feed, CROSSFEED_FEED_DEFAULT);
+            }
+            crossfeed => panic!("unexpected crossfeed {}", crossfeed),
+        }
+    }
+
+    #[test]
+    fn custom_parameters_are_used() {
+        match select(r#"{"Custom": {"fcut": 650, "feed": 9.5}}"#) {
+            Crossfeed::Custom { fcut, feed } => assert_eq!((fcut, feed), (650.0, 9.5)),
+            crossfeed => panic!("unexpected crossfeed {}", crossfeed),
+        }
+    }
+
+    #[test]
+    fn presets_are_selected_ignoring_case() {
+        assert_eq!(select(r#""mpm""#).to_string(), "Mpm");
+        assert_eq!(select(r#""none""#).to_string(), "None");
+    }
+}
@@ -0,0 +1,456 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+use thiserror::Error;
+
+use crate::configcreation::CamillaDspVersion;
+
+static DEFAULT_DEVICES: &[u8] = include_bytes!("data/default_devices.yml");
+static DEFAULT_DEVICES_V1: &[u8] = include_bytes!("data/default_devices_v1.yml");
+
+// the generated pipeline works on two channels
+const CHANNELS: usize = 2;
+
+#[derive(Debug)]
+pub enum DevicesFile {
+    Default,
+    Custom(String),
+    Built(Box<Devices>),
+}
+
+#[derive(Debug, Error)]
+pub enum DevicesError {
+    #[error("{file}: the 'devices' section could not be read: {reason}")]
+    Malformed { file: String, reason: String },
+    #[error("{file}: {field} has to be greater than 0")]
+    Zero { file: String, field: &'static str },
+    #[error("{file}: the {direction} device {backend} needs a '{field}'")]
+    MissingField {
+        file: String,
+        direction: &'static str,
+        backend: Backend,
+        field: &'static str,
+    },
+    #[error("{file}: the {direction} device has {channels} channels, the configuration needs {CHANNELS}")]
+    Channels {
+        file: String,
+        direction: &'static str,
+        channels: usize,
+    },
+    #[error("{file}: the {direction} device {backend} is not available in CamillaDSP {version}")]
+    UnsupportedBackend {
+        file: String,
+        direction: &'static str,
+        backend: Backend,
+        version: CamillaDspVersion,
+    },
+    #[error("{file}: the resampler {resampler} can not be used with CamillaDSP {version}")]
+    UnsupportedResampler {
+        file: String,
+        resampler: String,
+        version: CamillaDspVersion,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DevicesSection {
+    devices: Devices,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Devices {
+    pub samplerate: usize,
+    pub chunksize: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queuelimit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_samplerate: Option<usize>,
+    // up to version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enable_resampling: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resampler_type: Option<serde_yaml::Value>,
+    // since version 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resampler: Option<Resampler>,
+    // further options like rate adjustment are passed through as they are
+    #[serde(flatten)]
+    pub options: BTreeMap<String, serde_yaml::Value>,
+    pub capture: Device,
+    pub playback: Device,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub r#type: Backend,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<SampleFormat>,
+    #[serde(flatten)]
+    pub options: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backend {
+    Alsa,
+    CoreAudio,
+    Pulse,
+    Wasapi,
+    Jack,
+    Bluez,
+    Stdin,
+    Stdout,
+    File,
+    RawFile,
+    WavFile,
+    SignalGenerator,
+}
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// version 3 switched to the names used by Alsa
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SampleFormat {
+    #[serde(rename = "S16LE")]
+    S16Le,
+    #[serde(rename = "S24LE")]
+    S24Le,
+    #[serde(rename = "S24LE3")]
+    S24Le3,
+    #[serde(rename = "S32LE")]
+    S32Le,
+    #[serde(rename = "FLOAT32LE")]
+    Float32Le,
+    #[serde(rename = "FLOAT64LE")]
+    Float64Le,
+    #[serde(rename = "S16_LE")]
+    AlsaS16Le,
+    #[serde(rename = "S24_3_LE")]
+    AlsaS24Le3,
+    #[serde(rename = "S24_4_RJ_LE")]
+    AlsaS24Le4Rj,
+    #[serde(rename = "S24_4_LJ_LE")]
+    AlsaS24Le4Lj,
+    #[serde(rename = "S32_LE")]
+    AlsaS32Le,
+    #[serde(rename = "F32_LE")]
+    AlsaF32Le,
+    #[serde(rename = "F64_LE")]
+    AlsaF64Le,
+}
+impl SampleFormat {
+    pub fn for_version(self, version: CamillaDspVersion) -> SampleFormat {
+        use SampleFormat::*;
+        match version {
+            CamillaDspVersion::V1 | CamillaDspVersion::V2 => match self {
+                AlsaS16Le => S16Le,
+                AlsaS24Le3 => S24Le3,
+                AlsaS24Le4Rj => S24Le,
+                AlsaS24Le4Lj | AlsaS32Le => S32Le,
+                AlsaF32Le => Float32Le,
+                AlsaF64Le => Float64Le,
+                format => format,
+            },
+            CamillaDspVersion::V3 => match self {
+                S16Le => AlsaS16Le,
+                S24Le => AlsaS24Le4Rj,
+                S24Le3 => AlsaS24Le3,
+                S32Le => AlsaS32Le,
+                Float32Le => AlsaF32Le,
+                Float64Le => AlsaF64Le,
+                format => format,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Resampler {
+    AsyncSinc {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        profile: Option<SincProfile>,
+        #[serde(flatten)]
+        parameters: BTreeMap<String, serde_yaml::Value>,
+    },
+    AsyncPoly {
+        interpolation: serde_yaml::Value,
+    },
+    Synchronous,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SincProfile {
+    VeryFast,
+    Fast,
+    Balanced,
+    Accurate,
+}
+
+pub fn load_devices(devices_file: &DevicesFile, version: CamillaDspVersion) -> Result<Devices> {
+    let (file, content) = match devices_file {
+        DevicesFile::Default if version == CamillaDspVersion::V1 => {
+            ("default devices".to_string(), DEFAULT_DEVICES_V1.to_vec())
+        }
+        DevicesFile::Default => ("default devices".to_string(), DEFAULT_DEVICES.to_vec()),
+        DevicesFile::Custom(path) => (
+            path.clone(),
+            fs::read(path).context("Could not read file with custom devices section.")?,
+        ),
+        DevicesFile::Built(devices) => {
+            let mut devices = devices.as_ref().clone();
+            devices.set_version(version, "devices")?;
+            devices.validate(version, "devices")?;
+            return Ok(devices);
+        }
+    };
+    let section: DevicesSection =
+        serde_yaml::from_slice(&content).map_err(|error| DevicesError::Malformed {
+            file: file.clone(),
+            reason: error.to_string(),
+        })?;
+    let mut devices = section.devices;
+    devices.set_version(version, &file)?;
+    devices.validate(version, &file)?;
+    Ok(devices)
+}
+
+pub fn save_devices(devices: &Devices, path: &Path) -> Result<()> {
+    let section = DevicesSection {
+        devices: devices.clone(),
+    };
+    let yaml = serde_yaml::to_string(&section).context("The devices could not be serialized.")?;
+    fs::write(path, yaml)
+        .with_context(|| format!("Could not write devices file {}.", path.display()))?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct AlsaDevice {
+    pub card_id: String,
+    pub card_name: String,
+    pub device: usize,
+    pub name: String,
+    pub playback: bool,
+    pub capture: bool,
+}
+impl AlsaDevice {
+    // the card id keeps the name stable when the order of the cards changes
+    pub fn hw_name(&self) -> String {
+        format!("hw:{},{}", self.card_id, self.device)
+    }
+}
+impl fmt::Display for AlsaDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} — {}: {}", self.hw_name(), self.card_name, self.name)
+    }
+}
+
+// empty on systems without ALSA
+pub fn alsa_devices() -> Vec<AlsaDevice> {
+    let cards = fs::read_to_string("/proc/asound/cards").unwrap_or_default();
+    let pcm = fs::read_to_string("/proc/asound/pcm").unwrap_or_default();
+    parse_alsa_pcm(&pcm, &parse_alsa_cards(&cards))
+}
+
+// " 0 [PCH            ]: HDA-Intel - HDA Intel PCH" followed by an indented description line
+fn parse_alsa_cards(cards: &str) -> BTreeMap<usize, (String, String)> {
+    cards
+        .lines()
+        .filter_map(|line| {
+            let (index, rest) = line.trim_start().split_once(' ')?;
+            let index = index.parse().ok()?;
+            let (id, rest) = rest.trim_start().strip_prefix('[')?.split_once(']')?;
+            let name = match rest.split_once(" - ") {
+                Some((_, name)) => name,
+                None => rest.trim_start_matches(':'),
+            };
+            Some((index, (id.trim().to_string(), name.trim().to_string())))
+        })
+        .collect()
+}
+
+// "00-00: ALC892 Analog : ALC892 Analog : playback 1 : capture 1"
+fn parse_alsa_pcm(pcm: &str, cards: &BTreeMap<usize, (String, String)>) -> Vec<AlsaDevice> {
+    pcm.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(" : ");
+            let (address, name) = fields.next()?.split_once(':')?;
+            let (card, device) = address.split_once('-')?;
+            let card: usize = card.parse().ok()?;
+            let (card_id, card_name) = cards
+                .get(&card)
+                .cloned()
+                .unwrap_or_else(|| (card.to_string(), format!("Card {}", card)));
+            let streams: Vec<&str> = fields.map(str::trim).collect();
+            Some(AlsaDevice {
+                card_id,
+                card_name,
+                device: device.parse().ok()?,
+                name: name.trim().to_string(),
+                playback: streams.iter().any(|stream| stream.starts_with("playback")),
+                capture: streams.iter().any(|stream| stream.starts_with("capture")),
+            })
+        })
+        .collect()
+}
+
+impl Devices {
+    pub fn set_version(
+        &mut self,
+        version: CamillaDspVersion,
+        file: &str,
+    ) -> Result<(), DevicesError> {
+        for device in [&mut self.capture, &mut self.playback] {
+            device.format = device.format.map(|format| format.for_version(version));
+        }
+        // the File capture device was renamed in version 2
+        self.capture.r#type = match (version, self.capture.r#type) {
+            (CamillaDspVersion::V1, Backend::RawFile) => Backend::File,
+            (CamillaDspVersion::V2 | CamillaDspVersion::V3, Backend::File) => Backend::RawFile,
+            (_, backend) => backend,
+        };
+
+        let unsupported = |resampler: String| DevicesError::UnsupportedResampler {
+            file: file.to_string(),
+            resampler,
+            version,
+        };
+        match version {
+            CamillaDspVersion::V1 => {
+                if let Some(resampler) = self.resampler.take() {
+                    let resampler_type = match resampler {
+                        Resampler::Synchronous => "Synchronous",
+                        Resampler::AsyncSinc {
+                            profile: Some(SincProfile::Fast | SincProfile::VeryFast),
+                            ..
+                        } => "FastAsync",
+                        Resampler::AsyncSinc {
+                            profile: Some(SincProfile::Balanced),
+                            ..
+                        } => "BalancedAsync",
+                        Resampler::AsyncSinc {
+                            profile: Some(SincProfile::Accurate),
+                            ..
+                        } => "AccurateAsync",
+                        resampler => return Err(unsupported(format!("{:?}", resampler))),
+                    };
+                    self.enable_resampling = Some(true);
+                    self.resampler_type = Some(resampler_type.into());
+                }
+            }
+            CamillaDspVersion::V2 | CamillaDspVersion::V3 => {
+                let enabled = self.enable_resampling.take().unwrap_or(false);
+                if let Some(resampler_type) = self.resampler_type.take() {
+                    let profile = match resampler_type.as_str() {
+                        Some("Synchronous") => None,
+                        Some("FastAsync") => Some(SincProfile::Fast),
+                        Some("BalancedAsync") => Some(SincProfile::Balanced),
+                        Some("AccurateAsync") => Some(SincProfile::Accurate),
+                        _ => return Err(unsupported(format!("{:?}", resampler_type))),
+                    };
+                    if enabled {
+                        self.resampler = Some(match profile {
+                            Some(profile) => Resampler::AsyncSinc {
+                                profile: Some(profile),
+                                parameters: BTreeMap::new(),
+                            },
+                            None => Resampler::Synchronous,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self, version: CamillaDspVersion, file: &str) -> Result<(), DevicesError> {
+        let zero = |field| DevicesError::Zero {
+            file: file.to_string(),
+            field,
+        };
+        if self.samplerate == 0 {
+            return Err(zero("samplerate"));
+        }
+        if self.chunksize == 0 {
+            return Err(zero("chunksize"));
+        }
+        self.capture.validate("capture", version, file)?;
+        self.playback.validate("playback", version, file)
+    }
+}
+
+impl Device {
+    fn validate(
+        &self,
+        direction: &'static str,
+        version: CamillaDspVersion,
+        file: &str,
+    ) -> Result<(), DevicesError> {
+        let backend = self.r#type;
+        let in_version = match backend {
+            Backend::RawFile | Backend::WavFile => version >= CamillaDspVersion::V2,
+            Backend::Bluez | Backend::SignalGenerator => version >= CamillaDspVersion::V3,
+            _ => true,
+        };
+        let in_direction = match backend {
+            Backend::Stdin
+            | Backend::RawFile
+            | Backend::WavFile
+            | Backend::Bluez
+            | Backend::SignalGenerator => direction == "capture",
+            Backend::Stdout => direction == "playback",
+            _ => true,
+        };
+        if !in_version || !in_direction {
+            return Err(DevicesError::UnsupportedBackend {
+                file: file.to_string(),
+                direction,
+                backend,
+                version,
+            });
+        }
+
+        let missing = |field| DevicesError::MissingField {
+            file: file.to_string(),
+            direction,
+            backend,
+            field,
+        };
+        let needs_device = matches!(backend, Backend::Alsa | Backend::Pulse);
+        let needs_filename = matches!(backend, Backend::File | Backend::RawFile | Backend::WavFile);
+        let needs_format = matches!(
+            backend,
+            Backend::Pulse | Backend::Stdin | Backend::Stdout | Backend::File | Backend::RawFile
+        ) || (backend == Backend::Alsa && version < CamillaDspVersion::V3);
+        if needs_device && self.device.is_none() {
+            return Err(missing("device"));
+        }
+        if needs_filename && self.filename.is_none() {
+            return Err(missing("filename"));
+        }
+        if needs_format && self.format.is_none() {
+            return Err(missing("format"));
+        }
+
+        // wav files know their channel count
+        match (backend, self.channels) {
+            (Backend::WavFile, _) => Ok(()),
+            (_, None) => Err(missing("channels")),
+            (_, Some(CHANNELS)) => Ok(()),
+            (_, Some(channels)) => Err(DevicesError::Channels {
+                file: file.to_string(),
+                direction,
+                channels,
+            }),
+        }
+    }
+}
@@ -0,0 +1,664 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+use crate::biquad::{bandwidth_q, shelf_q, BiquadCoefficients};
+use crate::configcreation::{
+    BiquadParameters, Correction, DelayParameters, PeakingWidth, ShelfSteepness,
+};
+use crate::report::InputError;
+use crate::response::{gain_db, FrequencyResponse};
+use crate::scraping::GraphicEq;
+
+// sample rates the first order filters are calculated for in Equalizer APO and PipeWire
+const COEFFICIENT_SAMPLERATES: [f64; 6] = [44100.0, 48000.0, 88200.0, 96000.0, 176400.0, 192000.0];
+// the parametric bands are sampled at this rate for the graphic EQ formats
+const SAMPLERATE: f64 = 48000.0;
+const GRAPHIC_EQ_POINTS: usize = 127;
+const EASYEFFECTS_BANDS: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[value(rename_all = "verbatim")]
+pub enum OutputFormat {
+    #[default]
+    CamillaDsp,
+    EqualizerApo,
+    PipeWire,
+    EasyEffects,
+    JamesDsp,
+    Wavelet,
+}
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::CamillaDsp => "yml",
+            OutputFormat::EqualizerApo | OutputFormat::JamesDsp | OutputFormat::Wavelet => "txt",
+            OutputFormat::PipeWire => "conf",
+            OutputFormat::EasyEffects => "json",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// a pipeline step of CamillaDSP in a form the other EQ software can express
+#[derive(Debug)]
+pub enum Stage {
+    // sources of every output channel with linear gains, negative when inverted
+    Mix(Vec<Vec<(usize, f32)>>),
+    Filter {
+        channels: Vec<usize>,
+        filters: Vec<StageFilter>,
+    },
+}
+
+#[derive(Debug)]
+pub enum StageFilter {
+    Biquad(BiquadParameters),
+    // gain in dB
+    Gain(f32),
+    Graphic(GraphicEq),
+    Delay(DelayParameters),
+    // linear gain of inverted or muted gain filters, negative when inverted
+    Linear(f32),
+    // samples of a convolution filter at the sample rate of the pipeline
+    Impulse(Vec<f32>),
+}
+
+// the crossfeed stages come first, the correction is applied to both channels after them
+pub fn export_correction(
+    format: OutputFormat,
+    correction: &Correction,
+    crossfeed: Vec<Stage>,
+    description: &str,
+) -> Result<String> {
+    if !crossfeed.is_empty()
+        && matches!(
+            format,
+            OutputFormat::EasyEffects | OutputFormat::JamesDsp | OutputFormat::Wavelet
+        )
+    {
+        return Err(InputError(format!("A crossfeed can not be exported to {}.", format)).into());
+    }
+    let mut stages = crossfeed;
+    stages.push(Stage::Filter {
+        channels: vec![0, 1],
+        filters: match correction {
+            Correction::Parametric(eq_data) => [StageFilter::Gain(eq_data.gain)]
+                .into_iter()
+                .chain(eq_data.eq_bands.iter().cloned().map(StageFilter::Biquad))
+                .collect(),
+            Correction::Graphic(graphic_eq, _) => vec![StageFilter::Graphic(graphic_eq.clone())],
+        },
+    });
+
+    match format {
+        OutputFormat::CamillaDsp => {
+            Err(InputError("CamillaDSP configurations are not exported.".to_string()).into())
+        }
+        OutputFormat::EqualizerApo => equalizer_apo(&stages, description),
+        OutputFormat::PipeWire => pipewire(&stages, description),
+        OutputFormat::EasyEffects => easyeffects(&stages),
+        OutputFormat::JamesDsp | OutputFormat::Wavelet => graphic_eq(&stages),
+    }
+}
+
+fn unsupported(filter: &BiquadParameters, format: OutputFormat) -> anyhow::Error {
+    InputError(format!(
+        "The filter {:?} can not be exported to {}.",
+        filter, format
+    ))
+    .into()
+}
+
+// crossfeed presets can use filters other EQ software has nothing comparable for
+fn only_camilladsp(filter: &StageFilter) -> anyhow::Error {
+    let name = match filter {
+        StageFilter::Impulse(_) => "Convolution filters",
+        _ => "Inverted and muted gains",
+    };
+    InputError(format!("{} can only be used with CamillaDSP.", name)).into()
+}
+
+// at most four decimals without trailing zeros
+fn number(value: f32) -> String {
+    let text = format!("{:.4}", value);
+    match text.trim_end_matches('0').trim_end_matches('.') {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+fn db_to_linear(gain: f32) -> f32 {
+    10f32.powf(gain / 20.0)
+}
+
+fn equalizer_apo(stages: &[Stage], description: &str) -> Result<String> {
+    let mut lines = vec![format!("# {}", description)];
+    let mut names = vec!["L".to_string(), "R".to_string()];
+    let mut selection = "all".to_string();
+    for (i, stage) in stages.iter().enumerate() {
+        match stage {
+            Stage::Mix(outputs) => {
+                // mixers with other channel counts use virtual channels
+                let outputs_names: Vec<String> = match outputs.len() {
+                    2 => vec!["L".to_string(), "R".to_string()],
+                    n => (0..n).map(|dest| format!("M{}_{}", i, dest)).collect(),
+                };
+                let copies: Vec<String> = outputs
+                    .iter()
+                    .zip(&outputs_names)
+                    .map(|(sources, name)| {
+                        let sources: Vec<String> = sources
+                            .iter()
+                            .map(|(channel, gain)| format!("{}*{}", number(*gain), names[*channel]))
+                            .collect();
+                        match sources.is_empty() {
+                            true => format!("{}=0*{}", name, names[0]),
+                            false => format!("{}={}", name, sources.join("+")),
+                        }
+                    })
+                    .collect();
+                lines.push(format!("Copy: {}", copies.join(" ")));
+                names = outputs_names;
+            }
+            Stage::Filter { channels, filters } => {
+                let wanted =
+                    match names == ["L", "R"] && channels.contains(&0) && channels.contains(&1) {
+                        true => "all".to_string(),
+                        false => channels
+                            .iter()
+                            .map(|channel| names[*channel].clone())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    };
+                if wanted != selection {
+                    lines.push(format!("Channel: {}", wanted));
+                    selection = wanted;
+                }
+                for filter in filters {
+                    lines.extend(equalizer_apo_filter(filter)?);
+                }
+            }
+        }
+    }
+    if selection != "all" {
+        lines.push("Channel: all".to_string());
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+fn equalizer_apo_filter(filter: &StageFilter) -> Result<Vec<String>> {
+    let parameters = match filter {
+        StageFilter::Gain(gain) => return Ok(vec![format!("Preamp: {} dB", number(*gain))]),
+        StageFilter::Linear(gain) if *gain > 0.0 => {
+            return Ok(vec![format!("Preamp: {} dB", number(20.0 * gain.log10()))])
+        }
+        StageFilter::Delay(delay) => {
+            let delay = match delay.milliseconds() {
+                Some(delay) => format!("{} ms", number(delay)),
+                None => format!("{} samples", number(delay.delay)),
+            };
+            return Ok(vec![format!("Delay: {}", delay)]);
+        }
+        StageFilter::Graphic(graphic_eq) => {
+            let points: Vec<String> = graphic_eq
+                .points
+                .iter()
+                .map(|point| format!("{} {}", number(point.freq), number(point.gain)))
+                .collect();
+            return Ok(vec![
+                format!("Preamp: {} dB", number(graphic_eq.gain)),
+                format!("GraphicEQ: {}", points.join("; ")),
+            ]);
+        }
+        StageFilter::Linear(_) | StageFilter::Impulse(_) => return Err(only_camilladsp(filter)),
+        StageFilter::Biquad(parameters) => parameters,
+    };
+    let shelf = |kind: &str, steepness: &ShelfSteepness| match steepness {
+        ShelfSteepness::Q { freq, q, gain } => format!(
+            "{} Fc {} Hz Gain {} dB Q {}",
+            kind,
+            number(*freq),
+            number(*gain),
+            number(*q)
+        ),
+        ShelfSteepness::Slope { freq, slope, gain } => format!(
+            "{} {}dB Fc {} Hz Gain {} dB",
+            kind,
+            number(*slope),
+            number(*freq),
+            number(*gain)
+        ),
+    };
+    let filter = match parameters {
+        BiquadParameters::Peaking(PeakingWidth::Q { freq, q, gain }) => format!(
+            "PK Fc {} Hz Gain {} dB Q {}",
+            number(*freq),
+            number(*gain),
+            number(*q)
+        ),
+        BiquadParameters::Peaking(PeakingWidth::Bandwidth {
+            freq,
+            bandwidth,
+            gain,
+        }) => format!(
+            "PK Fc {} Hz Gain {} dB BW Oct {}",
+            number(*freq),
+            number(*gain),
+            number(*bandwidth)
+        ),
+        BiquadParameters::Lowshelf(steepness) => shelf("LSC", steepness),
+        BiquadParameters::Highshelf(steepness) => shelf("HSC", steepness),
+        BiquadParameters::LowshelfFO { freq, gain } => {
+            format!("LS 6dB Fc {} Hz Gain {} dB", number(*freq), number(*gain))
+        }
+        BiquadParameters::HighshelfFO { freq, gain } => {
+            format!("HS 6dB Fc {} Hz Gain {} dB", number(*freq), number(*gain))
+        }
+        BiquadParameters::Lowpass { freq, q } => {
+            format!("LPQ Fc {} Hz Q {}", number(*freq), number(*q))
+        }
+        BiquadParameters::Highpass { freq, q } => {
+            format!("HPQ Fc {} Hz Q {}", number(*freq), number(*q))
+        }
+        // there are no first order passes, their coefficients are selected by the sample rate
+        BiquadParameters::LowpassFO { .. } | BiquadParameters::HighpassFO { .. } => {
+            let mut lines = Vec::new();
+            for (i, samplerate) in COEFFICIENT_SAMPLERATES.iter().enumerate() {
+                let c = BiquadCoefficients::new(parameters, *samplerate);
+                let condition = match i {
+                    0 => "If",
+                    _ => "ElseIf",
+                };
+                lines.push(format!("{}: sampleRate == {}", condition, samplerate));
+                lines.push(format!(
+                    "Filter: ON IIR Order 1 Coefficients {} {} 1 {}",
+                    c.b0, c.b1, c.a1
+                ));
+            }
+            lines.push("EndIf:".to_string());
+            return Ok(lines);
+        }
+    };
+    Ok(vec![format!("Filter: ON {}", filter)])
+}
+
+fn pipewire(stages: &[Stage], description: &str) -> Result<String> {
+    let mut nodes: Vec<String> = (0..2)
+        .map(|channel| format!("{{ type = builtin name = in_{} label = copy }}", channel))
+        .collect();
+    let mut links = Vec::new();
+    let mut ports: Vec<String> = (0..2)
+        .map(|channel| format!("in_{}:Out", channel))
+        .collect();
+    let mut link = |from: &str, to: &str| {
+        links.push(format!("{{ output = \"{}\" input = \"{}\" }}", from, to))
+    };
+
+    for (i, stage) in stages.iter().enumerate() {
+        match stage {
+            Stage::Mix(outputs) => {
+                let mut mixed = Vec::new();
+                for (dest, sources) in outputs.iter().enumerate() {
+                    if sources.len() > 8 {
+                        return Err(InputError(
+                            "PipeWire mixers can not have more than 8 sources.".to_string(),
+                        )
+                        .into());
+                    }
+                    let name = format!("mix_{}_{}", i, dest);
+                    let controls: Vec<String> = sources
+                        .iter()
+                        .enumerate()
+                        .map(|(k, (_, gain))| format!("\"Gain {}\" = {}", k + 1, number(*gain)))
+                        .collect();
+                    nodes.push(format!(
+                        "{{ type = builtin name = {} label = mixer control = {{ {} }} }}",
+                        name,
+                        controls.join(" ")
+                    ));
+                    for (k, (channel, _)) in sources.iter().enumerate() {
+                        link(&ports[*channel], &format!("{}:In {}", name, k + 1));
+                    }
+                    mixed.push(format!("{}:Out", name));
+                }
+                ports = mixed;
+            }
+            Stage::Filter { channels, filters } => {
+                for channel in channels {
+                    for (k, filter) in filters.iter().enumerate() {
+                        let name = format!("eq_{}_{}_{}", i, channel, k);
+                        nodes.push(pipewire_node(&name, filter)?);
+                        let input = match filter {
+                            StageFilter::Gain(_) | StageFilter::Linear(_) => {
+                                format!("{}:In 1", name)
+                            }
+                            _ => format!("{}:In", name),
+                        };
+                        link(&ports[*channel], &input);
+                        ports[*channel] = format!("{}:Out", name);
+                    }
+                }
+            }
+        }
+    }
+    if ports.len() != 2 {
+        return Err(InputError(format!(
+            "PipeWire filter chains need 2 output channels, the crossfeed has {}.",
+            ports.len()
+        ))
+        .into());
+    }
+
+    let indent = |lines: Vec<String>| {
+        lines
+            .iter()
+            .map(|line| format!("                    {}", line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    Ok(format!(
+        include_str!("data/pipewire.conf"),
+        description = description,
+        nodes = indent(nodes),
+        links = indent(links),
+        outputs = ports
+            .iter()
+            .map(|port| format!("\"{}\"", port))
+            .collect::<Vec<_>>()
+            .join(" "),
+    ))
+}
+
+fn pipewire_node(name: &str, filter: &StageFilter) -> Result<String> {
+    let parameters = match filter {
+        StageFilter::Gain(gain) => {
+            return Ok(format!(
+                "{{ type = builtin name = {} label = mixer control = {{ \"Gain 1\" = {} }} }}",
+                name,
+                number(db_to_linear(*gain))
+            ))
+        }
+        StageFilter::Linear(gain) if *gain > 0.0 => {
+            return Ok(format!(
+                "{{ type = builtin name = {} label = mixer control = {{ \"Gain 1\" = {} }} }}",
+                name,
+                number(*gain)
+            ))
+        }
+        StageFilter::Graphic(_) => {
+            return Err(InputError(
+                "Graphic EQs can not be exported to PipeWire, use the parametric EQ.".to_string(),
+            )
+            .into())
+        }
+        // PipeWire only takes delays in seconds
+        StageFilter::Delay(delay) => {
+            let delay = delay.milliseconds().ok_or_else(|| {
+                InputError("Delays in samples can not be exported to PipeWire.".to_string())
+            })? / 1000.0;
+            return Ok(format!(
+                "{{ type = builtin name = {} label = delay config = {{ \"max-delay\" = {} }} control = {{ \"Delay (s)\" = {} }} }}",
+                name,
+                delay.ceil().max(1.0),
+                delay
+            ));
+        }
+        StageFilter::Linear(_) | StageFilter::Impulse(_) => return Err(only_camilladsp(filter)),
+        StageFilter::Biquad(parameters) => parameters,
+    };
+    let controls = |freq: f32, q: f32, gain: f32| {
+        format!(
+            "\"Freq\" = {} \"Q\" = {} \"Gain\" = {}",
+            number(freq),
+            number(q),
+            number(gain)
+        )
+    };
+    let (label, controls) = match parameters {
+        BiquadParameters::Peaking(PeakingWidth::Q { freq, q, gain }) => {
+            ("bq_peaking", controls(*freq, *q, *gain))
+        }
+        BiquadParameters::Peaking(PeakingWidth::Bandwidth {
+            freq,
+            bandwidth,
+            gain,
+        }) => (
+            "bq_peaking",
+            controls(*freq, bandwidth_q(*bandwidth), *gain),
+        ),
+        BiquadParameters::Lowshelf(steepness) | BiquadParameters::Highshelf(steepness) => {
+            let label = match parameters {
+                BiquadParameters::Lowshelf(_) => "bq_lowshelf",
+                _ => "bq_highshelf",
+            };
+            let (freq, gain) = match steepness {
+                ShelfSteepness::Q { freq, gain, .. } | ShelfSteepness::Slope { freq, gain, .. } => {
+                    (*freq, *gain)
+                }
+            };
+            (label, controls(freq, shelf_q(steepness), gain))
+        }
+        BiquadParameters::Lowpass { freq, q } => (
+            "bq_lowpass",
+            format!("\"Freq\" = {} \"Q\" = {}", number(*freq), number(*q)),
+        ),
+        BiquadParameters::Highpass { freq, q } => (
+            "bq_highpass",
+            format!("\"Freq\" = {} \"Q\" = {}", number(*freq), number(*q)),
+        ),
+        // PipeWire has no first order filters, their coefficients are given per sample rate
+        BiquadParameters::LowshelfFO { .. }
+        | BiquadParameters::HighshelfFO { .. }
+        | BiquadParameters::LowpassFO { .. }
+        | BiquadParameters::HighpassFO { .. } => {
+            let coefficients: Vec<String> = COEFFICIENT_SAMPLERATES
+                .iter()
+                .map(|samplerate| {
+                    let c = BiquadCoefficients::new(parameters, *samplerate);
+                    format!(
+                        "{{ rate = {} b0 = {} b1 = {} b2 = {} a0 = 1.0 a1 = {} a2 = {} }}",
+                        samplerate, c.b0, c.b1, c.b2, c.a1, c.a2
+                    )
+                })
+                .collect();
+            return Ok(format!(
+                "{{ type = builtin name = {} label = bq_raw config = {{ coefficients = [ {} ] }} }}",
+                name,
+                coefficients.join(" ")
+            ));
+        }
+    };
+    Ok(format!(
+        "{{ type = builtin name = {} label = {} control = {{ {} }} }}",
+        name, label, controls
+    ))
+}
+
+// the correction is the only stage without a crossfeed
+fn correction_filters(stages: &[Stage]) -> &[StageFilter] {
+    match stages {
+        [Stage::Filter { filters, .. }] => filters,
+        _ => &[],
+    }
+}
+
+fn easyeffects(stages: &[Stage]) -> Result<String> {
+    let mut preamp = 0.0;
+    let mut bands = Map::new();
+    for filter in correction_filters(stages) {
+        let parameters = match filter {
+            StageFilter::Gain(gain) => {
+                preamp += gain;
+                continue;
+            }
+            StageFilter::Graphic(_) => {
+                return Err(InputError(
+                    "Graphic EQs can not be exported to EasyEffects, use the parametric EQ."
+                        .to_string(),
+                )
+                .into())
+            }
+            StageFilter::Delay(_) => {
+                return Err(
+                    InputError("Delays can not be exported to EasyEffects.".to_string()).into(),
+                )
+            }
+            StageFilter::Linear(_) | StageFilter::Impulse(_) => {
+                return Err(only_camilladsp(filter))
+            }
+            StageFilter::Biquad(parameters) => parameters,
+        };
+        let (kind, freq, gain, q) = match parameters {
+            BiquadParameters::Peaking(PeakingWidth::Q { freq, q, gain }) => {
+                ("Bell", *freq, *gain, *q)
+            }
+            BiquadParameters::Peaking(PeakingWidth::Bandwidth {
+                freq,
+                bandwidth,
+                gain,
+            }) => ("Bell", *freq, *gain, bandwidth_q(*bandwidth)),
+            BiquadParameters::Lowshelf(steepness) | BiquadParameters::Highshelf(steepness) => {
+                let kind = match parameters {
+                    BiquadParameters::Lowshelf(_) => "Lo-shelf",
+                    _ => "Hi-shelf",
+                };
+                let (freq, gain) = match steepness {
+                    ShelfSteepness::Q { freq, gain, .. }
+                    | ShelfSteepness::Slope { freq, gain, .. } => (*freq, *gain),
+                };
+                (kind, freq, gain, shelf_q(steepness))
+            }
+            BiquadParameters::Lowpass { freq, q } => ("Lo-pass", *freq, 0.0, *q),
+            BiquadParameters::Highpass { freq, q } => ("Hi-pass", *freq, 0.0, *q),
+            _ => return Err(unsupported(parameters, OutputFormat::EasyEffects)),
+        };
+        bands.insert(
+            format!("band{}", bands.len()),
+            json!({
+                "frequency": freq,
+                "gain": gain,
+                "mode": "RLC (BT)",
+                "mute": false,
+                "q": q,
+                "slope": "x1",
+                "solo": false,
+                "type": kind,
+                "width": 4.0,
+            }),
+        );
+    }
+    if bands.len() > EASYEFFECTS_BANDS {
+        return Err(InputError(format!(
+            "EasyEffects supports {} bands, the correction has {}.",
+            EASYEFFECTS_BANDS,
+            bands.len()
+        ))
+        .into());
+    }
+
+    let num_bands = bands.len();
+    let channel = Value::Object(bands);
+    let preset = json!({
+        "output": {
+            "blocklist": [],
+            "equalizer#0": {
+                "balance": 0.0,
+                "bypass": false,
+                "input-gain": preamp,
+                "left": channel,
+                "mode": "IIR",
+                "num-bands": num_bands,
+                "output-gain": 0.0,
+                "pitch-left": 0.0,
+                "pitch-right": 0.0,
+                "right": channel,
+                "split-channels": false,
+            },
+            "plugins_order": ["equalizer#0"],
+        }
+    });
+    let mut preset = serde_json::to_string_pretty(&preset)
+        .context("The EasyEffects preset could not be serialized.")?;
+    preset.push('\n');
+    Ok(preset)
+}
+
+// JamesDSP and Wavelet read a single GraphicEQ line with the preamp included
+fn graphic_eq(stages: &[Stage]) -> Result<String> {
+    let freqs: Vec<f64> = (0..GRAPHIC_EQ_POINTS)
+        .map(|i| (20.0 * 1000f64.powf(i as f64 / (GRAPHIC_EQ_POINTS - 1) as f64)).round())
+        .collect();
+    // only the magnitude is exported
+    let response = FrequencyResponse::new(stages, 2, &freqs, SAMPLERATE)?;
+    let points: Vec<String> = freqs
+        .iter()
+        .zip(&response.transfer[0][0])
+        .map(|(freq, value)| format!("{} {:.1}", freq, gain_db(*value)))
+        .collect();
+    Ok(format!("GraphicEQ: {}\n", points.join("; ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configcreation::crossfeed_stages;
+    use crate::crossfeed::Crossfeed;
+    use crate::scraping::CorrectionFilterSet;
+
+    fn shelf_correction() -> Correction {
+        let mut correction = CorrectionFilterSet::new(-3.0);
+        correction
+            .eq_bands
+            .push(BiquadParameters::Lowshelf(ShelfSteepness::Slope {
+                freq: 105.0,
+                slope: 12.0,
+                gain: 3.0,
+            }));
+        Correction::Parametric(correction)
+    }
+
+    #[test]
+    fn equalizer_apo_gets_first_order_passes_for_every_sample_rate() {
+        let crossfeed = crossfeed_stages(&Crossfeed::Custom {
+            fcut: 700.0,
+            feed: 4.5,
+        })
+        .unwrap();
+        let content = export_correction(
+            OutputFormat::EqualizerApo,
+            &shelf_correction(),
+            crossfeed,
+            "Test",
+        )
+        .unwrap();
+        // one block for each of the two cross paths
+        assert_eq!(content.matches("If: sampleRate == 44100").count(), 2);
+        assert_eq!(
+            content
+                .matches("Filter: ON IIR Order 1 Coefficients")
+                .count(),
+            2 * COEFFICIENT_SAMPLERATES.len()
+        );
+        assert_eq!(content.matches("EndIf:").count(), 2);
+    }
+
+    #[test]
+    fn equalizer_apo_shelf_slopes_have_no_space_before_the_unit() {
+        let content = export_correction(
+            OutputFormat::EqualizerApo,
+            &shelf_correction(),
+            Vec::new(),
+            "Test",
+        )
+        .unwrap();
+        assert!(content.contains("Filter: ON LSC 12dB Fc 105 Hz Gain 3 dB"));
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::PI, fmt};
+
+use crate::scraping::GraphicEq;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+#[value(rename_all = "verbatim")]
+pub enum FirPhase {
+    #[default]
+    Minimum,
+    Linear,
+}
+
+impl fmt::Display for FirPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum ImpulseFormat {
+    #[default]
+    Wav,
+    Raw,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FirSettings {
+    pub phase: FirPhase,
+    pub taps: usize,
+    // defaults to the sample rate of the devices
+    pub samplerate: Option<usize>,
+    pub format: ImpulseFormat,
+}
+impl Default for FirSettings {
+    fn default() -> Self {
+        FirSettings {
+            phase: FirPhase::Minimum,
+            taps: 4096,
+            samplerate: None,
+            format: ImpulseFormat::Wav,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ImpulseFile {
+    pub filename: String,
+    pub samples: Vec<f32>,
+    pub samplerate: usize,
+    pub format: ImpulseFormat,
+}
+impl ImpulseFile {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(44 + self.samples.len() * 4);
+        if let ImpulseFormat::Wav = self.format {
+            write_wav_header(&mut bytes, self.samples.len(), self.samplerate);
+        }
+        for sample in self.samples.iter() {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+// mono 32 bit float
+fn write_wav_header(bytes: &mut Vec<u8>, length: usize, samplerate: usize) {
+    let data_size = (length * 4) as u32;
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&3u16.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&(samplerate as u32).to_le_bytes());
+    bytes.extend_from_slice(&(samplerate as u32 * 4).to_le_bytes());
+    bytes.extend_from_slice(&4u16.to_le_bytes());
+    bytes.extend_from_slice(&32u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+}
+
+pub fn impulse_response(
+    graphic_eq: &GraphicEq,
+    settings: &FirSettings,
+    samplerate: usize,
+) -> Result<Vec<f32>> {
+    if settings.taps < 16 || samplerate == 0 {
+        return Err(anyhow!(
+            "The FIR filter needs at least 16 taps and a valid sample rate."
+        ));
+    }
+    // a denser frequency grid than the filter length keeps time domain aliasing low
+    let fft_len = (settings.taps * 4).next_power_of_two();
+    let magnitudes = interpolate_magnitudes(graphic_eq, fft_len, samplerate);
+    let impulse = match settings.phase {
+        FirPhase::Linear => linear_phase(&magnitudes, fft_len, settings.taps),
+        FirPhase::Minimum => minimum_phase(&magnitudes, fft_len, settings.taps),
+    };
+    Ok(impulse)
+}
+
+// linear magnitudes for the bins 0..=fft_len/2, the preamp is a separate gain filter
+fn interpolate_magnitudes(graphic_eq: &GraphicEq, fft_len: usize, samplerate: usize) -> Vec<f32> {
+    let freqs: Vec<f32> = (0..=fft_len / 2)
+        .map(|bin| bin as f32 * samplerate as f32 / fft_len as f32)
+        .collect();
+    interpolate_gains(graphic_eq, &freqs)
+        .into_iter()
+        .map(|gain| 10f32.powf(gain / 20.0))
+        .collect()
+}
+
+// gains in dB without the preamp, interpolated on a logarithmic frequency axis
+pub fn interpolate_gains(graphic_eq: &GraphicEq, freqs: &[f32]) -> Vec<f32> {
+    let mut points: Vec<(f32, f32)> = graphic_eq
+        .points
+        .iter()
+        .map(|point| (point.freq.max(1.0).log2(), point.gain))
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    freqs
+        .iter()
+        .map(|freq| {
+            let freq = freq.max(1.0).log2();
+            let index = points.partition_point(|point| point.0 < freq);
+            match index {
+                0 => points[0].1,
+                i if i == points.len() => points[i - 1].1,
+                i => {
+                    let (f0, g0) = points[i - 1];
+                    let (f1, g1) = points[i];
+                    g0 + (g1 - g0) * (freq - f0) / (f1 - f0)
+                }
+            }
+        })
+        .collect()
+}
+
+fn full_spectrum(half: impl Fn(usize) -> Complex<f32>, fft_len: usize) -> Vec<Complex<f32>> {
+    (0..fft_len)
+        .map(|bin| match bin <= fft_len / 2 {
+            true => half(bin),
+            false => half(fft_len - bin).conj(),
+        })
+        .collect()
+}
+
+fn hann(position: f32) -> f32 {
+    0.5 - 0.5 * (2.0 * PI * position).cos()
+}
+
+fn linear_phase(magnitudes: &[f32], fft_len: usize, taps: usize) -> Vec<f32> {
+    let mut spectrum = full_spectrum(|bin| Complex::new(magnitudes[bin], 0.0), fft_len);
+    FftPlanner::new()
+        .plan_fft_inverse(fft_len)
+        .process(&mut spectrum);
+
+    // the zero phase response is centered around sample 0 and gets shifted by half the length
+    (0..taps)
+        .map(|i| {
+            let index = (i + fft_len - taps / 2) % fft_len;
+            spectrum[index].re / fft_len as f32 * hann((i as f32 + 0.5) / taps as f32)
+        })
+        .collect()
+}
+
+// homomorphic method based on the folded real cepstrum
+fn minimum_phase(magnitudes: &[f32], fft_len: usize, taps: usize) -> Vec<f32> {
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(fft_len);
+    let inverse = planner.plan_fft_inverse(fft_len);
+
+    let mut cepstrum = full_spectrum(
+        |bin| Complex::new(magnitudes[bin].max(1e-9).ln(), 0.0),
+        fft_len,
+    );
+    inverse.process(&mut cepstrum);
+    for (i, value) in cepstrum.iter_mut().enumerate() {
+        let fold = match i {
+            0 => 1.0,
+            i if i == fft_len / 2 => 1.0,
+            i if i < fft_len / 2 => 2.0,
+            _ => 0.0,
+        };
+        *value = Complex::new(value.re / fft_len as f32 * fold, 0.0);
+    }
+    forward.process(&mut cepstrum);
+    let mut spectrum: Vec<Complex<f32>> = cepstrum.iter().map(|value| value.exp()).collect();
+    inverse.process(&mut spectrum);
+
+    // fade out the last quarter to avoid a hard cut of the tail
+    let fade_start = taps - taps / 4;
+    (0..taps)
+        .map(|i| {
+            let fade = match i < fade_start {
+                true => 1.0,
+                false => hann(0.5 + 0.5 * (i - fade_start) as f32 / (taps - fade_start) as f32),
+            };
+            spectrum[i].re / fft_len as f32 * fade
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct WavFile {
+    pub samplerate: usize,
+    pub channels: Vec<Vec<f32>>,
+}
+
+// integer PCM and 32 or 64 bit float, also with the extensible format header
+pub fn read_wav(bytes: &[u8]) -> Result<WavFile> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(anyhow!("The file has no RIFF/WAVE header."));
+    }
+    let u16_at =
+        |chunk: &[u8], offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+    let u32_at = |chunk: &[u8], offset: usize| {
+        u32::from_le_bytes([
+            chunk[offset],
+            chunk[offset + 1],
+            chunk[offset + 2],
+            chunk[offset + 3],
+        ])
+    };
+
+    let mut format = None;
+    let mut data = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let size = u32_at(bytes, position + 4) as usize;
+        let end = (position + 8 + size).min(bytes.len());
+        let chunk = &bytes[position + 8..end];
+        match &bytes[position..position + 4] {
+            b"fmt " if chunk.len() >= 16 => {
+                let tag = match u16_at(chunk, 0) {
+                    0xFFFE if chunk.len() >= 26 => u16_at(chunk, 24),
+                    tag => tag,
+                };
+                format = Some((
+                    tag,
+                    u16_at(chunk, 2) as usize,
+                    u32_at(chunk, 4) as usize,
+                    u16_at(chunk, 14) as usize,
+                ));
+            }
+            b"data" => data = Some(chunk),
+            _ => (),
+        }
+        // chunks are padded to an even size
+        position += 8 + size + size % 2;
+    }
+    let (tag, channel_count, samplerate, bits) =
+        format.ok_or_else(|| anyhow!("The file has no format chunk."))?;
+    let data = data.ok_or_else(|| anyhow!("The file has no data chunk."))?;
+    if channel_count == 0 || samplerate == 0 {
+        return Err(anyhow!("The file has no channels or no sample rate."));
+    }
+
+    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+        (1, 16) => |sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+        (1, 24) => {
+            |sample| i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) as f32 / 2147483648.0
+        }
+        (1, 32) => |sample| {
+            i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32 / 2147483648.0
+        },
+        (3, 32) => |sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+        (3, 64) => |sample| {
+            f64::from_le_bytes([
+                sample[0], sample[1], sample[2], sample[3], sample[4], sample[5], sample[6],
+                sample[7],
+            ]) as f32
+        },
+        (tag, bits) => {
+            return Err(anyhow!(
+                "The sample format {} with {} bits is not supported.",
+                tag,
+                bits
+            ))
+        }
+    };
+    let sample_size = bits / 8;
+    let mut channels = vec![Vec::new(); channel_count];
+    for frame in data.chunks_exact(sample_size * channel_count) {
+        for (channel, sample) in channels.iter_mut().zip(frame.chunks_exact(sample_size)) {
+            channel.push(decode(sample));
+        }
+    }
+    Ok(WavFile {
+        samplerate,
+        channels,
+    })
+}
+
+// windowed sinc interpolation, scaled so the frequency response of an impulse stays the same
+pub fn resample(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    let ratio = to as f64 / from as f64;
+    // the kernel gets wider when downsampling to filter everything above the new nyquist
+    let bandwidth = ratio.min(1.0);
+    let half_width = 32.0 / bandwidth;
+    let sinc = |x: f64| match x == 0.0 {
+        true => 1.0,
+        false => (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x),
+    };
+
+    let length = (samples.len() as f64 * ratio).ceil() as usize;
+    (0..length)
+        .map(|n| {
+            let position = n as f64 / ratio;
+            let first = (position - half_width).ceil().max(0.0) as usize;
+            let last = ((position + half_width).floor() as usize).min(samples.len() - 1);
+            let sum: f64 = (first..=last)
+                .map(|k| {
+                    let distance = position - k as f64;
+                    let window = hann((0.5 + 0.5 * distance / half_width) as f32) as f64;
+                    samples[k] as f64 * bandwidth * sinc(bandwidth * distance) * window
+                })
+                .sum();
+            (sum / ratio) as f32
+        })
+        .collect()
+}
@@ -2,8 +2,9 @@ use anyhow::Result;
 use console::style;
 use indicatif::ProgressBar;
 
-use crate::configcreation::{build_configuration, write_yml_file};
-use crate::scraping::{scrape_eq_settings, scrape_links};
+use crate::configcreation::{build_configuration, write_yml_file, Correction, OutputSettings};
+use crate::report::FileExistsError;
+use crate::scraping::{load_database, read_correction, scrape_correction};
 use crate::userinterface::Cli;
 use crate::Config;
 
@@ -13,49 +14,87 @@ pub async fn interactive_mode(client: &reqwest::Client, config: &Config) -> Resu
     let mut cli = Cli::initialize();
 
     Cli::welcome();
-    progress_bar.set_message(format_msg("Loading Database...", &cli));
-    let database_result_list = scrape_links(client, &config.repo_url()).await?;
-    progress_bar.finish_with_message(format_msg("...Database loaded.", &cli));
+    cli.query_eq_file()?;
 
-    cli.select_headphone(&database_result_list)?;
-
-    progress_bar.set_message(format_msg("Loading EQ settings for {}...", &cli));
-
-    scrape_links(client, &config.headphone_url(&cli.headphone_url)).await?;
-
-    progress_bar.finish_with_message(format_msg("...EQ settings for {} loaded.", &cli));
+    let correction = match cli.eq_file.clone() {
+        Some(eq_file) => read_correction(&eq_file, &cli.fir),
+        None => load_autoeq_correction(client, config, &mut cli, &progress_bar).await,
+    };
+    let correction = match correction {
+        Ok(correction) => correction,
+        Err(error) => {
+            progress_bar.finish_with_message(format!(
+                "...Something went wrong unfortunately :(\n{}",
+                error
+            ));
+            return Err(error);
+        }
+    };
 
     cli.query_custom_devices()?;
 
-    cli.query_crossfeed()?;
+    cli.query_crossfeed(&config.crossfeed_presets)?;
+
+    cli.query_camilladsp_version()?;
 
     progress_bar.set_message(format_msg(
         "Parsing AutoEq settings for {} to CamillaDSP...",
         &cli,
     ));
 
-    let filterset = scrape_eq_settings(&config.headphone_url(&cli.headphone_url), client).await;
-    match filterset {
-        Ok(filterset) => {
-            let configuration = build_configuration(filterset, &cli.crossfeed)?;
-            write_yml_file(configuration, &cli.headphone, &cli.devices, &cli.crossfeed)?;
-
-            progress_bar.finish_with_message(format_msg(
-                "...Your config for CamillaDSP was created successfully. Happy listening! :)",
-                &cli,
-            ));
-        }
-        Err(error) => {
-            progress_bar.finish_with_message(format!(
-                "...Something went wrong unfortunately :(\n{}",
-                error
-            ));
+    let configuration = build_configuration(
+        correction,
+        &cli.crossfeed,
+        &cli.headphone,
+        &cli.devices,
+        cli.camilladsp_version,
+    )?;
+    let mut output = OutputSettings::default();
+    while let Err(error) = write_yml_file(
+        &configuration,
+        &cli.headphone,
+        None,
+        &cli.crossfeed,
+        &output,
+    ) {
+        match error.downcast_ref::<FileExistsError>() {
+            Some(exists) if !output.force && cli.confirm_overwrite(&exists.path)? => {
+                output.force = true;
+            }
+            _ => return Err(error),
         }
     }
 
+    progress_bar.finish_with_message(format_msg(
+        "...Your config for CamillaDSP was created successfully. Happy listening! :)",
+        &cli,
+    ));
+
     Ok(())
 }
 
+async fn load_autoeq_correction(
+    client: &reqwest::Client,
+    config: &Config,
+    cli: &mut Cli,
+    progress_bar: &ProgressBar,
+) -> Result<Correction> {
+    progress_bar.set_message(format_msg("Loading Database...", cli));
+    let database_result_list = load_database(client, config).await?;
+    progress_bar.finish_with_message(format_msg("...Database loaded.", cli));
+
+    cli.select_headphone(&database_result_list)?;
+
+    cli.query_eq_source()?;
+
+    progress_bar.set_message(format_msg("Loading EQ settings for {}...", cli));
+    let correction =
+        scrape_correction(client, config, &cli.headphone_url, cli.eq_source, &cli.fir).await?;
+    progress_bar.finish_with_message(format_msg("...EQ settings for {} loaded.", cli));
+
+    Ok(correction)
+}
+
 fn format_msg(msg: &str, cli: &Cli) -> String {
     let msg = msg.replace("{}", &cli.headphone);
     format!("\n{}", style(msg).magenta().bold())
@@ -1,18 +1,32 @@
+mod biquad;
+mod cache;
 mod configcreation;
+mod crossfeed;
+mod devices;
+mod export;
+mod fir;
 mod interactive;
 mod noninteractive;
+mod report;
+mod response;
 mod scraping;
 mod userinterface;
+mod websocket;
 
 use anyhow::{Context, Result};
+use clap::Args;
+use reqwest::Url;
 use serde::Deserialize;
+use std::{fs, path::PathBuf};
 
+use cache::Cache;
+use crossfeed::CrossfeedPresets;
 use interactive::interactive_mode;
-use noninteractive::{cli_mode_check, noninteractive_mode};
-
-// basic exit codes
-const EXIT_ERROR: i32 = 1; // catchall for errors
-const EXIT_OK: i32 = 0; // all fine
+use noninteractive::{
+    cli_mode_check, global_args, noninteractive_mode, print_error_json, GlobalArgs,
+};
+use report::{ConfigError, ErrorKind, Warnings, EXIT_OK};
+use scraping::EqSource;
 
 pub enum CliMode {
     Interactive,
@@ -21,45 +35,198 @@ pub enum CliMode {
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    github_url: String,
     github_raw: String,
     repo_url: String,
+    index: String,
     parametric_eq: String,
+    fixed_band_eq: String,
+    graphic_eq: String,
+    #[serde(skip)]
+    autoeq_dir: Option<PathBuf>,
+    #[serde(skip)]
+    cache: Cache,
+    #[serde(skip)]
+    crossfeed_presets: CrossfeedPresets,
+    #[serde(skip)]
+    warnings: Warnings,
 }
 impl Config {
-    fn load() -> Result<Self> {
-        let config: Config = serde_yaml::from_slice(include_bytes!("data/config.yml"))
-            .context("The configuration file could not be serialized")?;
+    // the built-in defaults are overridden by the user config file, environment variables and flags
+    fn load(args: GlobalArgs, warnings: Warnings) -> Result<Self> {
+        let mut config: Config = serde_yaml::from_slice(include_bytes!("data/config.yml"))
+            .context(ConfigError(
+                "The configuration file could not be serialized".to_string(),
+            ))?;
+        if let Some(user_config) = Config::load_user_config(args.config)? {
+            config.apply(user_config);
+        }
+        config.apply(args.overrides);
+        config.validate()?;
+        config.autoeq_dir = args.autoeq_dir;
+        config.cache = Cache::new(args.cache_ttl, args.refresh);
+        config.crossfeed_presets = CrossfeedPresets::load(args.crossfeed_dir, &warnings)?;
+        config.warnings = warnings;
         Ok(config)
     }
-    fn repo_url(&self) -> String {
-        format!("{}{}", self.github_url, self.repo_url)
+    fn load_user_config(path: Option<PathBuf>) -> Result<Option<ConfigOverrides>> {
+        let path = match path {
+            Some(path) => path,
+            None => match dirs::config_dir() {
+                Some(dir) if dir.join("autoeq2camilladsp/config.yml").is_file() => {
+                    dir.join("autoeq2camilladsp/config.yml")
+                }
+                _ => return Ok(None),
+            },
+        };
+        let user_config = fs::read(&path).with_context(|| {
+            ConfigError(format!(
+                "Could not read the config file {}.",
+                path.display()
+            ))
+        })?;
+        let user_config = serde_yaml::from_slice(&user_config).with_context(|| {
+            ConfigError(format!("The config file {} is invalid.", path.display()))
+        })?;
+        Ok(Some(user_config))
+    }
+    fn apply(&mut self, overrides: ConfigOverrides) {
+        let fields = [
+            (&mut self.github_raw, overrides.github_raw),
+            (&mut self.repo_url, overrides.repo_url),
+            (&mut self.index, overrides.index),
+            (&mut self.parametric_eq, overrides.parametric_eq),
+            (&mut self.fixed_band_eq, overrides.fixed_band_eq),
+            (&mut self.graphic_eq, overrides.graphic_eq),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+    }
+    fn validate(&self) -> Result<()> {
+        let results_url = self.results_url()?;
+        if !["http", "https"].contains(&results_url.scheme()) {
+            return Err(ConfigError(format!(
+                "The AutoEq URL {} has to use http or https.",
+                results_url
+            ))
+            .into());
+        }
+        self.index_url()?;
+        for eq_file in [
+            &self.index,
+            &self.parametric_eq,
+            &self.fixed_band_eq,
+            &self.graphic_eq,
+        ] {
+            if eq_file.is_empty() || eq_file.contains('/') {
+                return Err(
+                    ConfigError(format!("'{}' is not a valid AutoEq file name.", eq_file)).into(),
+                );
+            }
+        }
+        Ok(())
     }
-    fn headphone_url(&self, headphone_result: &str) -> String {
-        let headphone = headphone_result.split('/').last().unwrap();
-        format!(
-            "{}{}/{}%20{}",
-            self.github_raw,
-            headphone_result.replace("/blob", ""),
-            headphone,
-            self.parametric_eq,
-        )
+    fn github_raw_url(&self) -> Result<Url> {
+        let url = Url::parse(&self.github_raw)
+            .with_context(|| ConfigError(format!("Invalid AutoEq URL {}.", self.github_raw)))?;
+        if url.cannot_be_a_base() {
+            return Err(ConfigError(format!("Invalid AutoEq URL {}.", self.github_raw)).into());
+        }
+        Ok(with_trailing_slash(url))
     }
-    pub fn raw_eq_url(&self, eq_url: &str) -> String {
-        format!("{}{}", self.github_raw, eq_url.replace("/blob", ""))
+    fn results_url(&self) -> Result<Url> {
+        let results_url = self
+            .github_raw_url()?
+            .join(self.repo_url.trim_start_matches('/'))
+            .with_context(|| {
+                ConfigError(format!("Invalid AutoEq results path {}.", self.repo_url))
+            })?;
+        Ok(with_trailing_slash(results_url))
     }
+    fn index_url(&self) -> Result<String> {
+        let index_url = self
+            .results_url()?
+            .join(&self.index)
+            .with_context(|| ConfigError(format!("Invalid AutoEq index {}.", self.index)))?;
+        Ok(index_url.to_string())
+    }
+    fn eq_file(&self, eq_source: EqSource) -> &str {
+        match eq_source {
+            EqSource::Parametric => &self.parametric_eq,
+            EqSource::FixedBand => &self.fixed_band_eq,
+            EqSource::Graphic => &self.graphic_eq,
+        }
+    }
+    fn headphone_url(&self, headphone_result: &str, eq_source: EqSource) -> Result<String> {
+        let headphone = headphone_result.split('/').next_back().unwrap();
+        let mut url = self.results_url()?;
+        url.path_segments_mut()
+            .map_err(|_| ConfigError("The results URL can not be used as a base.".to_string()))?
+            .pop_if_empty()
+            .extend(headphone_result.split('/'))
+            .push(&format!("{} {}", headphone, self.eq_file(eq_source)));
+        Ok(url.to_string())
+    }
+    // the AutoEq directory can either be the repository root or its results directory
+    fn local_results_dir(&self) -> Option<PathBuf> {
+        self.autoeq_dir
+            .as_ref()
+            .map(|dir| match dir.join("results").is_dir() {
+                true => dir.join("results"),
+                false => dir.to_path_buf(),
+            })
+    }
+    fn headphone_path(&self, headphone_result: &str, eq_source: EqSource) -> Option<PathBuf> {
+        let headphone = headphone_result.split('/').next_back().unwrap();
+        self.local_results_dir().map(|dir| {
+            dir.join(headphone_result)
+                .join(format!("{} {}", headphone, self.eq_file(eq_source)))
+        })
+    }
+}
+
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default)]
+pub struct ConfigOverrides {
+    /// base URL of the raw AutoEq repository files e.g. a mirror
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_GITHUB_RAW")]
+    github_raw: Option<String>,
+    /// path of the AutoEq results directory below the base URL
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_REPO_URL")]
+    repo_url: Option<String>,
+    /// file name of the AutoEq results index
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_INDEX")]
+    index: Option<String>,
+    /// file name suffix of the parametric EQ results
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_PARAMETRIC_EQ")]
+    parametric_eq: Option<String>,
+    /// file name suffix of the fixed band EQ results
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_FIXED_BAND_EQ")]
+    fixed_band_eq: Option<String>,
+    /// file name suffix of the graphic EQ results
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_GRAPHIC_EQ")]
+    graphic_eq: Option<String>,
 }
 
 #[tokio::main]
-async fn run() -> Result<()> {
+async fn run(mode: &CliMode, warnings: Warnings) -> Result<()> {
     // setup
     let client = reqwest::Client::builder()
         .user_agent("AutoEq2CamillaDSP")
         .build()?;
-    let config = Config::load()?;
+    let config = Config::load(global_args(), warnings)?;
 
     // non-interactive mode if subcommand is provided
-    let mode = cli_mode_check();
     match mode {
         CliMode::Interactive => interactive_mode(&client, &config).await?,
         CliMode::NonInteractive => noninteractive_mode(&client, &config).await?,
@@ -69,10 +236,16 @@ async fn run() -> Result<()> {
 }
 
 fn main() {
-    let exitstatus = run();
+    let (mode, arguments) = cli_mode_check();
+    let warnings = Warnings::default();
+    let exitstatus = arguments.and_then(|_| run(&mode, warnings.clone()));
     match exitstatus {
-        Err(_) => {
-            std::process::exit(EXIT_ERROR);
+        Err(error) => {
+            let kind = ErrorKind::of(&error);
+            if let CliMode::NonInteractive = mode {
+                print_error_json(&error, kind, warnings.take());
+            }
+            std::process::exit(kind.exit_code());
         }
         Ok(_) => {
             std::process::exit(EXIT_OK);
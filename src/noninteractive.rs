@@ -1,10 +1,35 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
+use std::{
+    env, fmt, fs,
+    io::{self, Read},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 
-use crate::configcreation::{build_configuration, write_yml_file, Crossfeed, DevicesFile};
-use crate::scraping::{parse_filters, parse_preamp_gain, scrape_links, CorrectionFilterSet};
-use crate::{CliMode, Config};
+use crate::configcreation::{
+    build_configuration, crossfeed_stages, patch_configuration, read_configuration,
+    write_export_file, write_yml_file, BiquadParameters, CamillaDspVersion, Configuration,
+    Correction, OutputSettings, PeakingWidth, PipelinePosition, ShelfSteepness, WrittenFiles,
+};
+use crate::crossfeed::{
+    Crossfeed, CrossfeedParameters, CrossfeedPresets, CrossfeedSelection, HrirFiles,
+    CROSSFEED_FCUT, CROSSFEED_FCUT_DEFAULT, CROSSFEED_FEED, CROSSFEED_FEED_DEFAULT,
+};
+use crate::devices::DevicesFile;
+use crate::export::{export_correction, OutputFormat};
+use crate::fir::{FirPhase, FirSettings};
+use crate::report::{error_message, ErrorKind, InputError, UsageError};
+use crate::scraping::{
+    find_index_entry, headphone_name_from_path, load_database, read_correction, scrape_correction,
+    search_index, EqSource, GraphicEqPoint, IndexEntry,
+};
+use crate::websocket::{apply_configuration, ApplyReport};
+use crate::{CliMode, Config, ConfigOverrides};
+
+// in dB, a little above zero for the differences to the response AutoEq calculated the preamp with
+const MAX_PEAK_GAIN: f64 = 0.5;
 
 #[derive(Debug, Parser)]
 #[clap(name = "autoeq2camilladsp")]
@@ -12,6 +37,29 @@ use crate::{CliMode, Config};
 struct Cli {
     #[clap(subcommand)]
     command: Option<Commands>,
+    #[clap(flatten)]
+    global: GlobalArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// use a local clone or extracted archive of the AutoEq repository instead of GitHub
+    #[clap(long, global = true)]
+    pub autoeq_dir: Option<PathBuf>,
+    /// hours until the cached AutoEq index and EQ files expire
+    #[clap(long, global = true, default_value_t = 24)]
+    pub cache_ttl: u64,
+    /// ignore the cache and download everything again
+    #[clap(long, global = true)]
+    pub refresh: bool,
+    /// config file overriding the AutoEq URLs, defaults to autoeq2camilladsp/config.yml in your config directory
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// directory with additional crossfeed presets, defaults to autoeq2camilladsp/crossfeed in your config directory
+    #[clap(long, global = true, env = "AUTOEQ2CAMILLADSP_CROSSFEED_DIR")]
+    pub crossfeed_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    pub overrides: ConfigOverrides,
 }
 
 #[derive(Debug, Subcommand)]
@@ -19,50 +67,418 @@ enum Commands {
     /// output List of AutoEq entries and Crossfeed preset options as JSON
     Init,
     /// create a config file based on the provided selection
-    Create { input_json: String },
+    Create(Box<CreateArgs>),
+    /// replace the correction in an existing config file and keep everything else
+    Patch(Box<PatchArgs>),
+    /// send a config file to a running CamillaDSP over its websocket
+    Apply {
+        /// CamillaDSP config file
+        #[clap(value_name = "CONFIG")]
+        camilladsp_config: PathBuf,
+        /// websocket address of CamillaDSP e.g. ws://localhost:1234
+        url: String,
+        /// keep the new config even if CamillaDSP stops with it
+        #[clap(long)]
+        no_rollback: bool,
+    },
+    /// search the AutoEq index for headphones
+    Search {
+        /// part of the headphone name, matched fuzzily
+        query: String,
+        /// only list measurements of this source e.g. oratory1990
+        #[clap(long)]
+        source: Option<String>,
+        /// only list measurements of this headphone type or rig e.g. in-ear
+        #[clap(long = "type")]
+        headphone_type: Option<String>,
+        /// maximum number of results
+        #[clap(long, default_value_t = 20)]
+        limit: usize,
+        /// print the result as JSON or as a table
+        #[clap(long, value_enum, ignore_case = true, default_value_t)]
+        format: PrintFormat,
+    },
+    /// print the correction of a headphone without writing a config
+    Show {
+        /// name of the headphone as listed in the AutoEq index
+        headphone: String,
+        /// measurement source of the headphone e.g. oratory1990 or crinacle
+        #[clap(long)]
+        source: Option<String>,
+        /// measurement rig or headphone type for sources with several e.g. in-ear
+        #[clap(long)]
+        rig: Option<String>,
+        /// AutoEq result file to show
+        #[clap(long, value_enum, ignore_case = true, default_value_t)]
+        eq_source: EqSource,
+        /// print the result as JSON or as a table
+        #[clap(long, value_enum, ignore_case = true, default_value_t)]
+        format: PrintFormat,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+#[value(rename_all = "verbatim")]
+enum PrintFormat {
+    #[default]
+    Json,
+    Table,
+}
+impl fmt::Display for PrintFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// selection of the correction shared by create and patch
+#[derive(Debug, Args)]
+struct CorrectionArgs {
+    /// name of the headphone as listed in the AutoEq index
+    #[clap(long)]
+    headphone: Option<String>,
+    /// measurement source of the headphone e.g. oratory1990 or crinacle
+    #[clap(long)]
+    source: Option<String>,
+    /// measurement rig or headphone type for sources with several e.g. in-ear
+    #[clap(long)]
+    rig: Option<String>,
+    /// AutoEq result file to use for the correction
+    #[clap(long, value_enum, ignore_case = true)]
+    eq_source: Option<EqSource>,
+    /// phase of the FIR filter for the graphic EQ
+    #[clap(long, value_enum, ignore_case = true)]
+    fir_phase: Option<FirPhase>,
+    /// number of taps of the FIR filter for the graphic EQ
+    #[clap(long)]
+    fir_taps: Option<usize>,
+    /// sample rate of the FIR filter for the graphic EQ, defaults to the one of the devices
+    #[clap(long)]
+    fir_samplerate: Option<usize>,
+    /// CamillaDSP version the configuration is written for, defaults to the newest or the version of the patched file
+    #[clap(long, value_enum)]
+    camilladsp_version: Option<CamillaDspVersion>,
+    /// read the correction from a local ParametricEQ.txt, GraphicEQ.txt or Equalizer APO config
+    #[clap(long)]
+    eq_file: Option<PathBuf>,
+    /// name of the headphone for --eq-file, defaults to the file name
+    #[clap(long)]
+    name: Option<String>,
+}
+impl CorrectionArgs {
+    // flags take precedence over the JSON input
+    fn apply(&self, input: &mut InputJson) {
+        if let Some(name) = &self.headphone {
+            input.headphone = Some(Headphone {
+                name: name.clone(),
+                link: None,
+                source: self.source.clone(),
+                rig: self.rig.clone(),
+                headphone_type: None,
+            });
+        } else if let Some(headphone) = &mut input.headphone {
+            if let Some(source) = &self.source {
+                headphone.source = Some(source.clone());
+            }
+            if let Some(rig) = &self.rig {
+                headphone.rig = Some(rig.clone());
+            }
+        }
+        if let Some(eq_source) = self.eq_source {
+            input.eq_source = eq_source;
+        }
+        if let Some(version) = self.camilladsp_version {
+            input.camilladsp_version = Some(version);
+        }
+        if let Some(phase) = self.fir_phase {
+            input.fir.phase = phase;
+        }
+        if let Some(taps) = self.fir_taps {
+            input.fir.taps = taps;
+        }
+        if let Some(samplerate) = self.fir_samplerate {
+            input.fir.samplerate = Some(samplerate);
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ApplyArgs {
+    /// send the config to a running CamillaDSP over its websocket e.g. ws://localhost:1234
+    #[clap(long, value_name = "URL")]
+    apply: Option<String>,
+    /// keep the new config even if CamillaDSP stops with it
+    #[clap(long)]
+    no_rollback: bool,
+}
+
+#[derive(Debug, Args)]
+struct CreateArgs {
+    #[clap(flatten)]
+    correction: CorrectionArgs,
+    /// crossfeed preset, one of the crossfeedPresets listed by init
+    #[clap(long)]
+    crossfeed: Option<String>,
+    /// cutoff frequency of the Custom crossfeed in Hz
+    #[clap(long)]
+    crossfeed_fcut: Option<f32>,
+    /// feed level of the Custom crossfeed in dB
+    #[clap(long)]
+    crossfeed_feed: Option<f32>,
+    /// HRIR crossfeed from an ipsilateral and a contralateral mono WAV file or one with four channels
+    #[clap(long, num_args = 1..=2, value_name = "FILE")]
+    hrir: Vec<PathBuf>,
+    /// write the correction for other EQ software instead of CamillaDSP
+    #[clap(long, value_enum, ignore_case = true)]
+    format: Option<OutputFormat>,
+    /// file with a custom 'devices' section
+    #[clap(long)]
+    devices: Option<PathBuf>,
+    /// file or directory to write the configuration to, '-' for stdout
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// file name template with {headphone}, {source}, {crossfeed} and {samplerate}
+    #[clap(long)]
+    filename: Option<String>,
+    /// overwrite existing files
+    #[clap(long)]
+    force: bool,
+    /// read the selection as JSON from a file or from stdin with '-'
+    #[clap(long)]
+    json: Option<String>,
+    #[clap(flatten)]
+    apply: ApplyArgs,
+    /// selection as JSON string, use --json instead
+    #[clap(hide = true)]
+    input_json: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct PatchArgs {
+    /// existing CamillaDSP config file
+    #[clap(value_name = "CONFIG")]
+    camilladsp_config: PathBuf,
+    #[clap(flatten)]
+    correction: CorrectionArgs,
+    /// pipeline step the correction is inserted at: start, end or an index, defaults to the place of the previous correction
+    #[clap(long)]
+    position: Option<PipelinePosition>,
+    /// file or directory to write the patched configuration to, defaults to the config file itself
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// overwrite existing files
+    #[clap(long)]
+    force: bool,
+    #[clap(flatten)]
+    apply: ApplyArgs,
 }
 
 #[derive(Serialize)]
 struct OutputJson {
+    status: Status,
     #[serde(rename(serialize = "autoeqList"))]
     autoeq_list: Vec<Headphone>,
     #[serde(rename(serialize = "crossfeedPresets"))]
     crossfeed_presets: Vec<String>,
+    #[serde(rename(serialize = "customCrossfeed"))]
+    custom_crossfeed: CustomCrossfeedJson,
+    #[serde(rename(serialize = "eqSources"))]
+    eq_sources: Vec<String>,
+    #[serde(rename(serialize = "camilladspVersions"))]
+    camilladsp_versions: Vec<String>,
+    #[serde(rename(serialize = "outputFormats"))]
+    output_formats: Vec<String>,
+    warnings: Vec<String>,
 }
 impl OutputJson {
-    fn new() -> OutputJson {
+    fn new(crossfeed_presets: &CrossfeedPresets) -> OutputJson {
         OutputJson {
+            status: Status::Ok,
             autoeq_list: Vec::new(),
-            crossfeed_presets: vec![
-                Crossfeed::None.to_string(),
-                Crossfeed::PowChuMoy.to_string(),
-                Crossfeed::Mpm.to_string(),
-                Crossfeed::Natural.to_string(),
+            crossfeed_presets: crossfeed_presets.names(),
+            custom_crossfeed: CustomCrossfeedJson::new(),
+            eq_sources: vec![
+                EqSource::Parametric.to_string(),
+                EqSource::FixedBand.to_string(),
+                EqSource::Graphic.to_string(),
             ],
+            camilladsp_versions: CamillaDspVersion::value_variants()
+                .iter()
+                .filter_map(|version| version.to_possible_value())
+                .map(|version| version.get_name().to_string())
+                .collect(),
+            output_formats: OutputFormat::value_variants()
+                .iter()
+                .map(|format| format.to_string())
+                .collect(),
+            warnings: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct InputJson {
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Serialize)]
+struct CreateJson {
+    status: Status,
+    output: PathBuf,
+    #[serde(rename(serialize = "impulseFiles"))]
+    impulse_files: Vec<PathBuf>,
+    filters: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied: Option<ApplyReport>,
+    warnings: Vec<String>,
+}
+
+// parameter ranges of the Custom crossfeed
+#[derive(Serialize)]
+struct CustomCrossfeedJson {
+    fcut: ParameterJson,
+    feed: ParameterJson,
+}
+impl CustomCrossfeedJson {
+    fn new() -> Self {
+        CustomCrossfeedJson {
+            fcut: ParameterJson::new(&CROSSFEED_FCUT, CROSSFEED_FCUT_DEFAULT, "Hz"),
+            feed: ParameterJson::new(&CROSSFEED_FEED, CROSSFEED_FEED_DEFAULT, "dB"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ParameterJson {
+    min: f32,
+    max: f32,
+    default: f32,
+    unit: &'static str,
+}
+impl ParameterJson {
+    fn new(range: &RangeInclusive<f32>, default: f32, unit: &'static str) -> Self {
+        ParameterJson {
+            min: *range.start(),
+            max: *range.end(),
+            default,
+            unit,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApplyJson {
+    status: Status,
+    applied: ApplyReport,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SearchJson {
+    status: Status,
+    results: Vec<Headphone>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ShowJson<'a> {
+    status: Status,
     headphone: Headphone,
-    crossfeed: Crossfeed,
+    #[serde(rename(serialize = "eqSource"))]
+    eq_source: EqSource,
+    preamp: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bands: Option<&'a [BiquadParameters]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    points: Option<&'a [GraphicEqPoint]>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorJson {
+    status: Status,
+    error: ErrorDetails,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetails {
+    kind: ErrorKind,
+    message: String,
+    #[serde(rename(serialize = "exitCode"))]
+    exit_code: i32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct InputJson {
+    headphone: Option<Headphone>,
+    crossfeed: CrossfeedSelection,
+    #[serde(rename(deserialize = "eqSource"))]
+    eq_source: EqSource,
+    fir: FirSettings,
+    #[serde(rename(deserialize = "camilladspVersion"))]
+    camilladsp_version: Option<CamillaDspVersion>,
+    format: OutputFormat,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Headphone {
     name: String,
-    link: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rig: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    headphone_type: Option<String>,
+}
+impl From<&IndexEntry> for Headphone {
+    fn from(entry: &IndexEntry) -> Self {
+        Headphone {
+            name: entry.name.clone(),
+            link: Some(entry.path.clone()),
+            source: Some(entry.source.clone()),
+            rig: entry.rig.clone(),
+            headphone_type: entry.headphone_type.clone(),
+        }
+    }
 }
 
-pub fn cli_mode_check() -> CliMode {
-    let cli = Cli::parse();
-    match cli.command {
-        Some(_input) => CliMode::NonInteractive,
-        None => CliMode::Interactive,
+// invalid arguments are printed by clap and returned to be reported like every other error
+pub fn cli_mode_check() -> (CliMode, Result<()>) {
+    match Cli::try_parse() {
+        Ok(cli) => match cli.command {
+            Some(_input) => (CliMode::NonInteractive, Ok(())),
+            None => (CliMode::Interactive, Ok(())),
+        },
+        // help and version are no errors
+        Err(error) if !error.use_stderr() => error.exit(),
+        Err(error) => {
+            let _ = error.print();
+            // a subcommand selects the non-interactive mode even when its arguments are invalid
+            let args: Vec<String> = env::args().skip(1).collect();
+            let mode = match Cli::command()
+                .get_subcommands()
+                .any(|command| args.iter().any(|arg| arg == command.get_name()))
+            {
+                true => CliMode::NonInteractive,
+                false => CliMode::Interactive,
+            };
+            let message = error.to_string();
+            let message = message.lines().next().unwrap_or_default();
+            let message = message.strip_prefix("error: ").unwrap_or(message);
+            (mode, Err(UsageError(message.to_string()).into()))
+        }
     }
 }
 
+pub fn global_args() -> GlobalArgs {
+    Cli::parse().global
+}
+
 pub async fn noninteractive_mode(client: &reqwest::Client, config: &Config) -> Result<()> {
     let cli = Cli::parse();
     if let Some(input) = cli.command {
@@ -70,26 +486,166 @@ pub async fn noninteractive_mode(client: &reqwest::Client, config: &Config) -> R
             Commands::Init => {
                 create_json_output(client, config).await?;
             }
-            Commands::Create { input_json } => {
-                let input: InputJson = serde_json::from_str(&input_json)?;
-                create_config(client, config, input).await?;
+            Commands::Create(args) => {
+                let input = read_input_json(&args)?;
+                create_config(client, config, input, *args).await?;
+            }
+            Commands::Patch(args) => {
+                let mut input = InputJson::default();
+                args.correction.apply(&mut input);
+                patch_config(client, config, input, *args).await?;
+            }
+            Commands::Apply {
+                camilladsp_config: path,
+                url,
+                no_rollback,
+            } => {
+                let mut configuration = read_configuration(&path)?;
+                configuration.resolve_impulse_paths(path.parent().unwrap_or(Path::new(".")))?;
+                let json = ApplyJson {
+                    status: Status::Ok,
+                    applied: apply_configuration(&url, &configuration, !no_rollback).await?,
+                    warnings: config.warnings.take(),
+                };
+                println!("{}", serde_json::to_string(&json)?);
+            }
+            Commands::Search {
+                query,
+                source,
+                headphone_type,
+                limit,
+                format,
+            } => {
+                let database = load_database(client, config).await?;
+                let results: Vec<&IndexEntry> = search_index(
+                    &database,
+                    &query,
+                    source.as_deref(),
+                    headphone_type.as_deref(),
+                )
+                .into_iter()
+                .take(limit)
+                .collect();
+                print_search_results(config, &results, format)?;
+            }
+            Commands::Show {
+                headphone,
+                source,
+                rig,
+                eq_source,
+                format,
+            } => {
+                let database = load_database(client, config).await?;
+                let entry =
+                    find_index_entry(&database, &headphone, source.as_deref(), rig.as_deref())?;
+                let correction = scrape_correction(
+                    client,
+                    config,
+                    &entry.path,
+                    eq_source,
+                    &FirSettings::default(),
+                )
+                .await?;
+                print_correction(config, entry, eq_source, &correction, format)?;
             }
         }
     }
     Ok(())
 }
 
-async fn create_json_output(client: &reqwest::Client, config: &Config) -> Result<()> {
-    let database_result_list = scrape_links(client, &config.repo_url()).await?;
+// flags take precedence over the JSON input
+fn read_input_json(args: &CreateArgs) -> Result<InputJson> {
+    let input_json = match (&args.json, &args.input_json) {
+        (Some(path), _) if path == "-" => {
+            let mut input_json = String::new();
+            io::stdin()
+                .read_to_string(&mut input_json)
+                .context(InputError(
+                    "Could not read JSON input from stdin.".to_string(),
+                ))?;
+            Some(input_json)
+        }
+        (Some(path), _) => Some(
+            fs::read_to_string(path)
+                .with_context(|| format!("Could not read JSON input from {}.", path))?,
+        ),
+        (None, input_json) => input_json.clone(),
+    };
+    let mut input: InputJson = match input_json {
+        Some(input_json) => serde_json::from_str(&input_json).context("Invalid JSON input.")?,
+        None => InputJson::default(),
+    };
 
-    let mut json = OutputJson::new();
-    for (key, val) in database_result_list.iter() {
-        json.autoeq_list.push(Headphone {
-            name: key.to_owned(),
-            link: val.to_owned(),
+    args.correction.apply(&mut input);
+    if let Some(crossfeed) = &args.crossfeed {
+        input.crossfeed = CrossfeedSelection::Name(crossfeed.clone());
+    }
+    if args.crossfeed_fcut.is_some() || args.crossfeed_feed.is_some() {
+        let (fcut, feed) = match &input.crossfeed {
+            CrossfeedSelection::Name(name) if name.eq_ignore_ascii_case("custom") => {
+                (CROSSFEED_FCUT_DEFAULT, CROSSFEED_FEED_DEFAULT)
+            }
+            CrossfeedSelection::Parameters(CrossfeedParameters::Custom { fcut, feed }) => {
+                (*fcut, *feed)
+            }
+            _ => {
+                return Err(InputError(
+                    "--crossfeed-fcut and --crossfeed-feed need --crossfeed Custom.".to_string(),
+                )
+                .into())
+            }
+        };
+        input.crossfeed = CrossfeedSelection::Parameters(CrossfeedParameters::Custom {
+            fcut: args.crossfeed_fcut.unwrap_or(fcut),
+            feed: args.crossfeed_feed.unwrap_or(feed),
         });
     }
+    if !args.hrir.is_empty() {
+        if let Some(crossfeed) = args
+            .crossfeed
+            .as_ref()
+            .filter(|crossfeed| !crossfeed.eq_ignore_ascii_case("hrir"))
+        {
+            return Err(InputError(format!(
+                "--hrir cannot be used with the {} crossfeed.",
+                crossfeed
+            ))
+            .into());
+        }
+        let files = match args.hrir.as_slice() {
+            [speakers] => HrirFiles::Speakers {
+                speakers: speakers.clone(),
+            },
+            [ipsilateral, contralateral] => HrirFiles::Pair {
+                ipsilateral: ipsilateral.clone(),
+                contralateral: contralateral.clone(),
+            },
+            // --hrir can be repeated
+            _ => {
+                return Err(InputError(
+                    "--hrir takes either two mono files or one file with four channels."
+                        .to_string(),
+                )
+                .into())
+            }
+        };
+        input.crossfeed = CrossfeedSelection::Parameters(CrossfeedParameters::Hrir(files));
+    }
+    if let Some(format) = args.format {
+        input.format = format;
+    }
+    Ok(input)
+}
+
+async fn create_json_output(client: &reqwest::Client, config: &Config) -> Result<()> {
+    let database_result_list = load_database(client, config).await?;
 
+    let mut json = OutputJson::new(&config.crossfeed_presets);
+    for entry in database_result_list.iter() {
+        json.autoeq_list.push(Headphone::from(entry));
+    }
+
+    json.warnings = config.warnings.take();
     let json_out = serde_json::to_string(&json)?;
 
     println!("{}", json_out);
@@ -97,41 +653,459 @@ async fn create_json_output(client: &reqwest::Client, config: &Config) -> Result
     Ok(())
 }
 
-async fn create_config(client: &reqwest::Client, config: &Config, input: InputJson) -> Result<()> {
-    let filterset = create_filterset(client, config, &input.headphone.link).await;
+fn print_search_results(
+    config: &Config,
+    results: &[&IndexEntry],
+    format: PrintFormat,
+) -> Result<()> {
+    match format {
+        PrintFormat::Json => {
+            let json = SearchJson {
+                status: Status::Ok,
+                results: results
+                    .iter()
+                    .map(|entry| Headphone::from(*entry))
+                    .collect(),
+                warnings: config.warnings.take(),
+            };
+            println!("{}", serde_json::to_string(&json)?);
+        }
+        PrintFormat::Table => {
+            println!("{:<40} {:<16} Rig", "Name", "Source");
+            for entry in results.iter() {
+                println!(
+                    "{:<40} {:<16} {}",
+                    entry.name,
+                    entry.source,
+                    entry.details().unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
 
-    match filterset {
-        Ok(filterset) => {
-            let configuration = build_configuration(filterset, &input.crossfeed)?;
-            write_yml_file(
-                configuration,
-                &input.headphone.name,
-                &DevicesFile::Default,
-                &input.crossfeed,
-            )?;
-            Ok(())
+fn print_correction(
+    config: &Config,
+    entry: &IndexEntry,
+    eq_source: EqSource,
+    correction: &Correction,
+    format: PrintFormat,
+) -> Result<()> {
+    let (preamp, bands, points) = match correction {
+        Correction::Parametric(filter_set) => {
+            (filter_set.gain, Some(filter_set.eq_bands.as_slice()), None)
         }
-        Err(error) => {
-            println!("...Something went wrong unfortunately :(\n{}", error);
-            Err(error)
+        Correction::Graphic(graphic_eq, _) => {
+            (graphic_eq.gain, None, Some(graphic_eq.points.as_slice()))
+        }
+    };
+    match format {
+        PrintFormat::Json => {
+            let json = ShowJson {
+                status: Status::Ok,
+                headphone: Headphone::from(entry),
+                eq_source,
+                preamp,
+                bands,
+                points,
+                warnings: config.warnings.take(),
+            };
+            println!("{}", serde_json::to_string(&json)?);
+        }
+        PrintFormat::Table => {
+            println!("{}\n", entry);
+            println!("Preamp: {:.1} dB\n", preamp);
+            if let Some(bands) = bands {
+                println!(
+                    "{:<4} {:<12} {:>10} {:>10}   Q / Slope",
+                    "#", "Type", "Freq (Hz)", "Gain (dB)"
+                );
+                for (i, band) in bands.iter().enumerate() {
+                    let (filter_type, freq, gain, width) = band_columns(band);
+                    let gain = gain.map(|gain| format!("{:.1}", gain)).unwrap_or_default();
+                    println!(
+                        "{:<4} {:<12} {:>10.1} {:>10}   {}",
+                        i + 1,
+                        filter_type,
+                        freq,
+                        gain,
+                        width
+                    );
+                }
+            }
+            if let Some(points) = points {
+                println!("{:>10} {:>10}", "Freq (Hz)", "Gain (dB)");
+                for point in points.iter() {
+                    println!("{:>10.1} {:>10.1}", point.freq, point.gain);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn band_columns(band: &BiquadParameters) -> (&str, f32, Option<f32>, String) {
+    let q = |q: &f32| format!("Q {:.2}", q);
+    let slope = |slope: &f32| format!("{:.1} dB/oct", slope);
+    match band {
+        BiquadParameters::Highpass { freq, q: width } => ("Highpass", *freq, None, q(width)),
+        BiquadParameters::Lowpass { freq, q: width } => ("Lowpass", *freq, None, q(width)),
+        BiquadParameters::Peaking(PeakingWidth::Q {
+            freq,
+            q: width,
+            gain,
+        }) => ("Peaking", *freq, Some(*gain), q(width)),
+        BiquadParameters::Peaking(PeakingWidth::Bandwidth {
+            freq,
+            bandwidth,
+            gain,
+        }) => (
+            "Peaking",
+            *freq,
+            Some(*gain),
+            format!("BW {:.2} oct", bandwidth),
+        ),
+        BiquadParameters::Lowshelf(ShelfSteepness::Q {
+            freq,
+            q: width,
+            gain,
+        }) => ("Lowshelf", *freq, Some(*gain), q(width)),
+        BiquadParameters::Lowshelf(ShelfSteepness::Slope {
+            freq,
+            slope: width,
+            gain,
+        }) => ("Lowshelf", *freq, Some(*gain), slope(width)),
+        BiquadParameters::Highshelf(ShelfSteepness::Q {
+            freq,
+            q: width,
+            gain,
+        }) => ("Highshelf", *freq, Some(*gain), q(width)),
+        BiquadParameters::Highshelf(ShelfSteepness::Slope {
+            freq,
+            slope: width,
+            gain,
+        }) => ("Highshelf", *freq, Some(*gain), slope(width)),
+        BiquadParameters::HighshelfFO { freq, gain } => {
+            ("HighshelfFO", *freq, Some(*gain), String::new())
+        }
+        BiquadParameters::LowshelfFO { freq, gain } => {
+            ("LowshelfFO", *freq, Some(*gain), String::new())
+        }
+        BiquadParameters::HighpassFO { freq } => ("HighpassFO", *freq, None, String::new()),
+        BiquadParameters::LowpassFO { freq } => ("LowpassFO", *freq, None, String::new()),
+    }
+}
+
+pub fn print_error_json(error: &anyhow::Error, kind: ErrorKind, warnings: Vec<String>) {
+    let json = ErrorJson {
+        status: Status::Error,
+        error: ErrorDetails {
+            kind,
+            message: error_message(error),
+            exit_code: kind.exit_code(),
+        },
+        warnings,
+    };
+    if let Ok(json_out) = serde_json::to_string(&json) {
+        println!("{}", json_out);
+    }
+}
+
+// the headphone name and measurement source with the correction
+async fn load_correction(
+    client: &reqwest::Client,
+    config: &Config,
+    input: &mut InputJson,
+    args: &CorrectionArgs,
+) -> Result<(String, Option<String>, Result<Correction>)> {
+    let loaded = match &args.eq_file {
+        Some(path) => {
+            let headphone_name = args
+                .name
+                .clone()
+                .or(input.headphone.take().map(|headphone| headphone.name))
+                .unwrap_or_else(|| headphone_name_from_path(path));
+            (headphone_name, None, read_correction(path, &input.fir))
         }
+        None => {
+            let headphone = input.headphone.take().ok_or_else(|| {
+                InputError("Please select a headphone or provide an EQ file.".to_string())
+            })?;
+            let (headphone_name, link) = match headphone.link {
+                Some(link) => (headphone.name, link),
+                None => {
+                    let database = load_database(client, config).await?;
+                    // the type is as good as the rig to tell measurements of one source apart
+                    let entry = find_index_entry(
+                        &database,
+                        &headphone.name,
+                        headphone.source.as_deref(),
+                        headphone
+                            .rig
+                            .as_deref()
+                            .or(headphone.headphone_type.as_deref()),
+                    )?;
+                    (entry.name.clone(), entry.path.clone())
+                }
+            };
+            // the first segment of the link is the measurement source
+            let source = link.split('/').next().map(String::from);
+            let correction =
+                scrape_correction(client, config, &link, input.eq_source, &input.fir).await;
+            (headphone_name, source, correction)
+        }
+    };
+    Ok(loaded)
+}
+
+async fn create_config(
+    client: &reqwest::Client,
+    config: &Config,
+    mut input: InputJson,
+    args: CreateArgs,
+) -> Result<()> {
+    let crossfeed = config.crossfeed_presets.select(&input.crossfeed)?;
+    let (headphone_name, source, correction) =
+        load_correction(client, config, &mut input, &args.correction).await?;
+    if input.format != OutputFormat::CamillaDsp {
+        return export_config(
+            config,
+            input.format,
+            &crossfeed,
+            args,
+            headphone_name,
+            source,
+            correction?,
+        );
     }
+    let devices = match args.devices {
+        Some(path) => DevicesFile::Custom(path.display().to_string()),
+        None => DevicesFile::Default,
+    };
+
+    let mut configuration = build_configuration(
+        correction?,
+        &crossfeed,
+        &headphone_name,
+        &devices,
+        input.camilladsp_version.unwrap_or_default(),
+    )?;
+    check_headroom(config, &configuration);
+    let filters = configuration.filter_names();
+    let output = OutputSettings {
+        path: args.output,
+        filename: args.filename,
+        force: args.force,
+    };
+    let written_files = write_yml_file(
+        &configuration,
+        &headphone_name,
+        source.as_deref(),
+        &crossfeed,
+        &output,
+    )?;
+    let applied = apply_written_config(&mut configuration, &written_files, &args.apply).await?;
+
+    let json = CreateJson {
+        status: Status::Ok,
+        output: written_files.config,
+        impulse_files: written_files.impulse_files,
+        filters,
+        applied,
+        warnings: config.warnings.take(),
+    };
+    // stdout is taken by the configuration itself
+    match json.output == Path::new("-") {
+        true => eprintln!("{}", serde_json::to_string(&json)?),
+        false => println!("{}", serde_json::to_string(&json)?),
+    }
+
+    Ok(())
 }
 
-async fn create_filterset(
+// configurations without devices, with processors or unreadable impulse files are not checked,
+// relative impulse files are read next to the configuration
+fn check_headroom(config: &Config, configuration: &Configuration) {
+    if let Ok((gain, freq)) = configuration.peak_gain() {
+        if gain > MAX_PEAK_GAIN {
+            config.warnings.push(format!(
+                "The configuration raises the level by up to {:.1} dB at {:.0} Hz, which can clip.",
+                gain, freq
+            ));
+        }
+    }
+}
+
+// other EQ software has no devices and can not be applied to CamillaDSP
+fn export_config(
+    config: &Config,
+    format: OutputFormat,
+    crossfeed: &Crossfeed,
+    args: CreateArgs,
+    headphone_name: String,
+    source: Option<String>,
+    correction: Correction,
+) -> Result<()> {
+    if args.devices.is_some() || args.apply.apply.is_some() {
+        return Err(InputError(format!(
+            "--devices and --apply can not be used with the format {}.",
+            format
+        ))
+        .into());
+    }
+    let description = format!(
+        "AutoEq correction for {} with {} crossfeed, created by autoeq2camilladsp",
+        headphone_name, crossfeed
+    );
+    let content = export_correction(
+        format,
+        &correction,
+        crossfeed_stages(crossfeed)?,
+        &description,
+    )?;
+    let output = OutputSettings {
+        path: args.output,
+        filename: args.filename,
+        force: args.force,
+    };
+    let path = write_export_file(
+        &content,
+        &headphone_name,
+        source.as_deref(),
+        crossfeed,
+        format,
+        &output,
+    )?;
+
+    let json = CreateJson {
+        status: Status::Ok,
+        output: path,
+        impulse_files: Vec::new(),
+        filters: Vec::new(),
+        applied: None,
+        warnings: config.warnings.take(),
+    };
+    match json.output == Path::new("-") {
+        true => eprintln!("{}", serde_json::to_string(&json)?),
+        false => println!("{}", serde_json::to_string(&json)?),
+    }
+    Ok(())
+}
+
+async fn patch_config(
     client: &reqwest::Client,
     config: &Config,
-    link: &str,
-) -> Result<CorrectionFilterSet> {
-    let eq_file = client
-        .get(config.headphone_url(link))
-        .send()
-        .await?
-        .text()
-        .await?;
-    let mut data = eq_file.lines();
-    let preamp_gain = parse_preamp_gain(&mut data)?;
-    let mut filterset = CorrectionFilterSet::new(preamp_gain);
-    parse_filters(&mut data, &mut filterset)?;
-    Ok(filterset)
+    mut input: InputJson,
+    args: PatchArgs,
+) -> Result<()> {
+    let (headphone_name, source, correction) =
+        load_correction(client, config, &mut input, &args.correction).await?;
+
+    let mut configuration = patch_configuration(
+        &args.camilladsp_config,
+        correction?,
+        &headphone_name,
+        args.position,
+        input.camilladsp_version,
+        &config.warnings,
+    )?;
+    check_headroom(config, &configuration);
+    let filters = configuration.filter_names();
+    // writing the result back is what patching is for
+    let output = OutputSettings {
+        force: args.force || args.output.is_none(),
+        path: Some(args.output.unwrap_or(args.camilladsp_config)),
+        filename: None,
+    };
+    let written_files = write_yml_file(
+        &configuration,
+        &headphone_name,
+        source.as_deref(),
+        &Crossfeed::None,
+        &output,
+    )?;
+    let applied = apply_written_config(&mut configuration, &written_files, &args.apply).await?;
+
+    let json = CreateJson {
+        status: Status::Ok,
+        output: written_files.config,
+        impulse_files: written_files.impulse_files,
+        filters,
+        applied,
+        warnings: config.warnings.take(),
+    };
+    match json.output == Path::new("-") {
+        true => eprintln!("{}", serde_json::to_string(&json)?),
+        false => println!("{}", serde_json::to_string(&json)?),
+    }
+
+    Ok(())
+}
+
+async fn apply_written_config(
+    configuration: &mut Configuration,
+    written_files: &WrittenFiles,
+    args: &ApplyArgs,
+) -> Result<Option<ApplyReport>> {
+    let Some(url) = &args.apply else {
+        return Ok(None);
+    };
+    // impulse files are written next to the config or to the current directory for stdout
+    let directory = written_files.config.parent().unwrap_or(Path::new("."));
+    configuration.resolve_impulse_paths(directory)?;
+    let report = apply_configuration(url, configuration, !args.no_rollback)
+        .await
+        .with_context(|| {
+            format!(
+                "{} was written but could not be applied",
+                written_files.config.display()
+            )
+        })?;
+    Ok(Some(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_takes_the_user_config_next_to_the_camilladsp_config() {
+        let cli = Cli::try_parse_from([
+            "autoeq2camilladsp",
+            "patch",
+            "--config",
+            "settings.yml",
+            "room.yml",
+            "--headphone",
+            "Sennheiser HD 650",
+        ])
+        .unwrap();
+        assert_eq!(cli.global.config, Some(PathBuf::from("settings.yml")));
+        let Some(Commands::Patch(args)) = cli.command else {
+            panic!("patch was not parsed");
+        };
+        assert_eq!(args.camilladsp_config, PathBuf::from("room.yml"));
+    }
+
+    #[test]
+    fn apply_takes_the_user_config_next_to_the_camilladsp_config() {
+        let cli = Cli::try_parse_from([
+            "autoeq2camilladsp",
+            "apply",
+            "room.yml",
+            "ws://localhost:1234",
+            "--config",
+            "settings.yml",
+        ])
+        .unwrap();
+        assert_eq!(cli.global.config, Some(PathBuf::from("settings.yml")));
+        let Some(Commands::Apply {
+            camilladsp_config, ..
+        }) = cli.command
+        else {
+            panic!("apply was not parsed");
+        };
+        assert_eq!(camilladsp_config, PathBuf::from("room.yml"));
+    }
 }
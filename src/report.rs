@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+use crate::devices::DevicesError;
+use crate::scraping::ScrapingError;
+use crate::websocket::ApplyError;
+
+// exit codes, documented in the readme
+pub const EXIT_OK: i32 = 0;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    Internal,
+    InvalidInput,
+    Config,
+    NotFound,
+    Network,
+    InvalidEqFile,
+    Io,
+    FileExists,
+    Rejected,
+    Usage,
+}
+impl ErrorKind {
+    pub fn of(error: &anyhow::Error) -> ErrorKind {
+        if error.downcast_ref::<FileExistsError>().is_some() {
+            return ErrorKind::FileExists;
+        }
+        if error.downcast_ref::<ConfigError>().is_some() {
+            return ErrorKind::Config;
+        }
+        if error.downcast_ref::<UsageError>().is_some() {
+            return ErrorKind::Usage;
+        }
+        if error.downcast_ref::<InputError>().is_some()
+            || error.downcast_ref::<DevicesError>().is_some()
+            || error.downcast_ref::<serde_json::Error>().is_some()
+        {
+            return ErrorKind::InvalidInput;
+        }
+        if let Some(error) = error.downcast_ref::<ScrapingError>() {
+            return match error {
+                ScrapingError::HeadphoneNotFound { .. } => ErrorKind::NotFound,
+                ScrapingError::AmbiguousHeadphone { .. } => ErrorKind::InvalidInput,
+                ScrapingError::HttpStatus { status, .. } if status.as_u16() == 404 => {
+                    ErrorKind::NotFound
+                }
+                ScrapingError::HttpStatus { .. } => ErrorKind::Network,
+                _ => ErrorKind::InvalidEqFile,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<ApplyError>() {
+            return match error {
+                ApplyError::Connection { .. } | ApplyError::Protocol { .. } => ErrorKind::Network,
+                _ => ErrorKind::Rejected,
+            };
+        }
+        if error.downcast_ref::<reqwest::Error>().is_some() {
+            return ErrorKind::Network;
+        }
+        match error.downcast_ref::<io::Error>() {
+            Some(error) if error.kind() == io::ErrorKind::NotFound => ErrorKind::NotFound,
+            Some(_) => ErrorKind::Io,
+            None => ErrorKind::Internal,
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::InvalidInput => 2,
+            ErrorKind::Config => 3,
+            ErrorKind::NotFound => 4,
+            ErrorKind::Network => 5,
+            ErrorKind::InvalidEqFile => 6,
+            ErrorKind::Io => 7,
+            ErrorKind::FileExists => 8,
+            ErrorKind::Rejected => 9,
+            ErrorKind::Usage => 10,
+        }
+    }
+}
+
+// the error chain in one line, without causes repeating the previous message
+pub fn error_message(error: &anyhow::Error) -> String {
+    let mut message = String::new();
+    for cause in error.chain() {
+        let cause = cause.to_string();
+        if message.contains(&cause) {
+            continue;
+        }
+        if !message.is_empty() {
+            message.push_str(match message.ends_with('.') {
+                true => " ",
+                false => ": ",
+            });
+        }
+        message.push_str(&cause);
+    }
+    message
+}
+
+// invalid AutoEq URLs or config files
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct ConfigError(pub String);
+
+// invalid selections in the non-interactive mode
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct InputError(pub String);
+
+// invalid command line arguments
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct UsageError(pub String);
+
+#[derive(Debug, Error)]
+#[error("{} already exists.", path.display())]
+pub struct FileExistsError {
+    pub path: PathBuf,
+}
+
+// collected during a run and reported with the result in the non-interactive mode
+#[derive(Debug, Default, Clone)]
+pub struct Warnings(Arc<Mutex<Vec<String>>>);
+impl Warnings {
+    pub fn push(&self, warning: String) {
+        eprintln!("Warning: {}", warning);
+        if let Ok(mut warnings) = self.0.lock() {
+            warnings.push(warning);
+        }
+    }
+
+    pub fn take(&self) -> Vec<String> {
+        match self.0.lock() {
+            Ok(mut warnings) => warnings.drain(..).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
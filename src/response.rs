@@ -0,0 +1,280 @@
+use anyhow::{anyhow, Result};
+use rustfft::num_complex::Complex;
+use std::f64::consts::PI;
+
+use crate::biquad::BiquadCoefficients;
+use crate::export::{Stage, StageFilter};
+use crate::fir::interpolate_gains;
+
+// complex responses from every input to every output channel of a pipeline
+#[derive(Debug, Clone)]
+pub struct FrequencyResponse {
+    pub freqs: Vec<f64>,
+    // indexed by output channel, input channel and frequency
+    pub transfer: Vec<Vec<Vec<Complex<f64>>>>,
+}
+impl FrequencyResponse {
+    // mixers sum up the paths of their sources, so crossfeed paths are added with their phase
+    pub fn new(stages: &[Stage], inputs: usize, freqs: &[f64], samplerate: f64) -> Result<Self> {
+        let zero = vec![Complex::new(0.0, 0.0); freqs.len()];
+        let mut transfer: Vec<Vec<Vec<Complex<f64>>>> = (0..inputs)
+            .map(|output| {
+                (0..inputs)
+                    .map(|input| match input == output {
+                        true => vec![Complex::new(1.0, 0.0); freqs.len()],
+                        false => zero.clone(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        for stage in stages {
+            match stage {
+                Stage::Mix(outputs) => {
+                    transfer = outputs
+                        .iter()
+                        .map(|sources| {
+                            let mut output = vec![zero.clone(); inputs];
+                            for (channel, gain) in sources {
+                                let source = transfer.get(*channel).ok_or_else(|| {
+                                    anyhow!("The mixer source {} does not exist.", channel)
+                                })?;
+                                for (total, path) in output.iter_mut().zip(source) {
+                                    for (total, value) in total.iter_mut().zip(path) {
+                                        *total += value * *gain as f64;
+                                    }
+                                }
+                            }
+                            Ok(output)
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                Stage::Filter { channels, filters } => {
+                    let mut response = vec![Complex::new(1.0, 0.0); freqs.len()];
+                    for filter in filters {
+                        for (total, value) in response
+                            .iter_mut()
+                            .zip(filter_response(filter, freqs, samplerate))
+                        {
+                            *total *= value;
+                        }
+                    }
+                    for channel in channels {
+                        let paths = transfer.get_mut(*channel).ok_or_else(|| {
+                            anyhow!("The filter channel {} does not exist.", channel)
+                        })?;
+                        for path in paths.iter_mut() {
+                            for (value, filter) in path.iter_mut().zip(&response) {
+                                *value *= filter;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(FrequencyResponse {
+            freqs: freqs.to_vec(),
+            transfer,
+        })
+    }
+}
+
+// graphic EQs only have a magnitude, so their phase is zero
+pub fn filter_response(filter: &StageFilter, freqs: &[f64], samplerate: f64) -> Vec<Complex<f64>> {
+    match filter {
+        StageFilter::Gain(gain) => {
+            vec![Complex::new(10f64.powf(*gain as f64 / 20.0), 0.0); freqs.len()]
+        }
+        StageFilter::Biquad(parameters) => {
+            let biquad = BiquadCoefficients::new(parameters, samplerate);
+            freqs
+                .iter()
+                .map(|freq| biquad.response(*freq, samplerate))
+                .collect()
+        }
+        StageFilter::Delay(parameters) => {
+            let seconds = match parameters.milliseconds() {
+                Some(milliseconds) => milliseconds as f64 / 1000.0,
+                None => parameters.delay as f64 / samplerate,
+            };
+            freqs
+                .iter()
+                .map(|freq| Complex::from_polar(1.0, -2.0 * PI * freq * seconds))
+                .collect()
+        }
+        StageFilter::Linear(gain) => vec![Complex::new(*gain as f64, 0.0); freqs.len()],
+        StageFilter::Impulse(samples) => freqs
+            .iter()
+            .map(|freq| {
+                let step = Complex::from_polar(1.0, -2.0 * PI * freq / samplerate);
+                // Horner's method from the last sample
+                samples
+                    .iter()
+                    .rev()
+                    .fold(Complex::new(0.0, 0.0), |total, sample| {
+                        total * step + *sample as f64
+                    })
+            })
+            .collect(),
+        StageFilter::Graphic(graphic_eq) => {
+            let freqs: Vec<f32> = freqs.iter().map(|freq| *freq as f32).collect();
+            interpolate_gains(graphic_eq, &freqs)
+                .into_iter()
+                .map(|gain| Complex::new(10f64.powf((graphic_eq.gain + gain) as f64 / 20.0), 0.0))
+                .collect()
+        }
+    }
+}
+
+pub fn gain_db(value: Complex<f64>) -> f64 {
+    20.0 * value.norm().log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configcreation::{
+        build_configuration, crossfeed_stages, BiquadParameters, CamillaDspVersion, Correction,
+        PeakingWidth, ShelfSteepness,
+    };
+    use crate::crossfeed::Crossfeed;
+    use crate::devices::DevicesFile;
+    use crate::fir::FirSettings;
+    use crate::scraping::read_correction;
+    use std::path::Path;
+
+    const SAMPLERATE: f64 = 48000.0;
+
+    fn gains(filter: StageFilter, freqs: &[f64]) -> Vec<f64> {
+        filter_response(&filter, freqs, SAMPLERATE)
+            .into_iter()
+            .map(gain_db)
+            .collect()
+    }
+
+    fn assert_close(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (value - expected).abs() < tolerance,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn peaking_filters_have_their_gain_at_the_center() {
+        let peaking = StageFilter::Biquad(BiquadParameters::Peaking(PeakingWidth::Q {
+            freq: 1000.0,
+            q: 1.41,
+            gain: 6.0,
+        }));
+        let gains = gains(peaking, &[10.0, 1000.0, 20000.0]);
+        assert_close(gains[0], 0.0, 0.01);
+        assert_close(gains[1], 6.0, 0.001);
+        assert_close(gains[2], 0.0, 0.05);
+    }
+
+    #[test]
+    fn shelves_reach_their_gain_and_half_of_it_at_the_center() {
+        let shelf = ShelfSteepness::Q {
+            freq: 1000.0,
+            q: 0.707,
+            gain: -6.0,
+        };
+        let low = gains(
+            StageFilter::Biquad(BiquadParameters::Lowshelf(shelf.clone())),
+            &[5.0, 1000.0, 20000.0],
+        );
+        assert_close(low[0], -6.0, 0.01);
+        assert_close(low[1], -3.0, 0.01);
+        assert_close(low[2], 0.0, 0.05);
+        let high = gains(
+            StageFilter::Biquad(BiquadParameters::Highshelf(shelf)),
+            &[5.0, 1000.0, 20000.0],
+        );
+        assert_close(high[0], 0.0, 0.01);
+        assert_close(high[1], -3.0, 0.01);
+        assert_close(high[2], -6.0, 0.05);
+    }
+
+    #[test]
+    fn impulses_are_evaluated_at_the_sample_rate() {
+        let freqs = [0.0, SAMPLERATE / 4.0];
+        // a delay by one sample
+        let delay = filter_response(&StageFilter::Impulse(vec![0.0, 1.0]), &freqs, SAMPLERATE);
+        assert_close(delay[1].norm(), 1.0, 1e-9);
+        assert_close(delay[1].arg(), -PI / 2.0, 1e-9);
+        let average = gains(StageFilter::Impulse(vec![0.5, 0.5]), &freqs);
+        assert_close(average[0], 0.0, 1e-9);
+        assert_close(average[1], -3.0103, 0.001);
+    }
+
+    #[test]
+    fn mixers_sum_up_their_sources() {
+        // the right channel is inverted, so a mix of both cancels out
+        let stages = [
+            Stage::Filter {
+                channels: vec![1],
+                filters: vec![StageFilter::Linear(-1.0)],
+            },
+            Stage::Mix(vec![vec![(0, 1.0), (1, 1.0)], vec![(0, 0.5)]]),
+        ];
+        let response = FrequencyResponse::new(&stages, 2, &[100.0], SAMPLERATE).unwrap();
+        let sum = response.transfer[0][0][0] + response.transfer[0][1][0];
+        assert_close(sum.norm(), 0.0, 1e-9);
+        assert_close(response.transfer[1][0][0].re, 0.5, 1e-9);
+        assert_close(response.transfer[1][1][0].norm(), 0.0, 1e-9);
+    }
+
+    #[test]
+    fn crossfeed_paths_add_up_at_low_frequencies() {
+        let feed = 4.5;
+        let stages = crossfeed_stages(&Crossfeed::Custom { fcut: 700.0, feed }).unwrap();
+        let response = FrequencyResponse::new(&stages, 2, &[5.0], SAMPLERATE).unwrap();
+        let direct = response.transfer[0][0][0];
+        let cross = response.transfer[0][1][0];
+        // the levels bs2b gives the direct and the lowpassed cross path
+        let gain_low = -5.0 / 6.0 * feed as f64 - 3.0;
+        let gain_high = feed as f64 / 6.0 - 3.0;
+        assert_close(gain_db(direct), gain_high, 0.01);
+        assert_close(gain_db(cross), gain_low, 0.01);
+        assert_close(
+            gain_db(direct + cross),
+            20.0 * (10f64.powf(gain_low / 20.0) + 10f64.powf(gain_high / 20.0)).log10(),
+            0.01,
+        );
+        assert_close(gain_db(response.transfer[1][1][0]), gain_high, 0.01);
+        assert_close(gain_db(response.transfer[1][0][0]), gain_low, 0.01);
+    }
+
+    #[test]
+    fn fir_corrections_follow_the_graphic_eq() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(
+            "tests/fixtures/AutoEq/results/crinacle/GRAS 43AG-7 over-ear/Sennheiser HD 650/Sennheiser HD 650 GraphicEQ.txt",
+        );
+        let correction = read_correction(&path, &FirSettings::default()).unwrap();
+        let preamp = match &correction {
+            Correction::Graphic(graphic_eq, _) => graphic_eq.gain as f64,
+            Correction::Parametric(_) => panic!("the fixture is a graphic EQ"),
+        };
+        let configuration = build_configuration(
+            correction,
+            &Crossfeed::None,
+            "Sennheiser HD 650",
+            &DevicesFile::Default,
+            CamillaDspVersion::V3,
+        )
+        .unwrap();
+        let response = configuration.frequency_response(&[100.0, 1000.0]).unwrap();
+        let gains: Vec<f64> = response.transfer[0][0]
+            .iter()
+            .copied()
+            .map(gain_db)
+            .collect();
+        // the points are interpolated on a logarithmic frequency scale
+        let at_100 = -1.0 + (100f64 / 20.0).log10() / (1000f64 / 20.0).log10();
+        assert_close(gains[0], preamp + at_100, 0.2);
+        assert_close(gains[1], preamp, 0.2);
+        assert_close(response.transfer[0][1][0].norm(), 0.0, 1e-9);
+    }
+}
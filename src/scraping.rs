@@ -1,9 +1,28 @@
-use anyhow::{anyhow, Result};
-use scraper::{Html, Selector};
-use serde::Serialize;
-use std::collections::HashMap;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path};
+use thiserror::Error;
 
-use crate::configcreation::{BiquadParameters, PeakingWidth};
+use crate::configcreation::{BiquadParameters, Correction, PeakingWidth, ShelfSteepness};
+use crate::fir::FirSettings;
+use crate::Config;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+#[value(rename_all = "verbatim")]
+pub enum EqSource {
+    #[default]
+    Parametric,
+    FixedBand,
+    Graphic,
+}
+
+impl fmt::Display for EqSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
 #[derive(Debug, Serialize)]
 pub struct CorrectionFilterSet {
@@ -19,107 +38,881 @@ impl CorrectionFilterSet {
     }
 }
 
-#[derive(Debug)]
-pub struct Link {
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphicEq {
+    pub gain: f32,
+    pub points: Vec<GraphicEqPoint>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphicEqPoint {
+    pub freq: f32,
+    pub gain: f32,
+}
+
+#[derive(Debug, Error)]
+pub enum ScrapingError {
+    #[error("{file}, line {line}: malformed preamp gain '{token}'")]
+    MalformedPreamp {
+        file: String,
+        line: usize,
+        token: String,
+    },
+    #[error("{file}, line {line}: malformed filter band at '{token}', {reason}")]
+    MalformedBand {
+        file: String,
+        line: usize,
+        token: String,
+        reason: String,
+    },
+    #[error("{file}, line {line}: unsupported filter type '{filter_type}'")]
+    UnsupportedFilterType {
+        file: String,
+        line: usize,
+        filter_type: String,
+    },
+    #[error("{file}, line {line}: malformed graphic EQ point '{token}'")]
+    MalformedPoint {
+        file: String,
+        line: usize,
+        token: String,
+    },
+    #[error("'{name}' could not be found in the AutoEq index")]
+    HeadphoneNotFound { name: String },
+    #[error("'{name}' was measured several times, please choose a source and rig of: {sources}")]
+    AmbiguousHeadphone { name: String, sources: String },
+    #[error("{file}: the file is empty")]
+    EmptyFile { file: String },
+    #[error("{url}: the request failed with HTTP status {status}")]
+    HttpStatus {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+}
+
+const HEADPHONE_TYPES: [&str; 3] = ["over-ear", "in-ear", "earbud"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexEntry {
     pub name: String,
-    pub url: String,
+    pub source: String,
+    pub rig: Option<String>,
+    #[serde(rename(serialize = "type"))]
+    pub headphone_type: Option<String>,
+    pub path: String,
+}
+impl IndexEntry {
+    // the path looks like "source/rig type/name" e.g. "crinacle/GRAS 43AG-7 over-ear/..."
+    fn from_path(name: String, path: String) -> Self {
+        let segments: Vec<&str> = path.split('/').collect();
+        let directory = match segments.len() > 2 {
+            true => segments[1..segments.len() - 1].join("/"),
+            false => String::new(),
+        };
+        let headphone_type = HEADPHONE_TYPES
+            .into_iter()
+            .find(|headphone_type| directory.contains(headphone_type));
+        // older results have directories like "harman_over-ear_2018" which are kept as they are
+        let rig = directory
+            .strip_suffix(headphone_type.unwrap_or_default())
+            .unwrap_or(&directory)
+            .trim();
+        IndexEntry {
+            name,
+            source: segments[0].to_string(),
+            rig: (!rig.is_empty()).then(|| rig.to_string()),
+            headphone_type: headphone_type.map(String::from),
+            path,
+        }
+    }
+
+    // rig and headphone type as far as they are known
+    pub fn details(&self) -> Option<String> {
+        let details: Vec<&str> = [&self.rig, &self.headphone_type]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        (!details.is_empty()).then(|| details.join(" "))
+    }
+
+    // e.g. "in-ear" for the headphone type or "GRAS 43AG-7" for the rig
+    pub fn matches_rig(&self, rig: &str) -> bool {
+        let rig = rig.trim().to_lowercase();
+        [&self.rig, &self.headphone_type]
+            .into_iter()
+            .flatten()
+            .any(|value| value.to_lowercase().contains(&rig))
+    }
 }
-impl Link {
-    pub fn new(name: String, url: String) -> Self {
-        Link { name, url }
+
+impl fmt::Display for IndexEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.details() {
+            Some(details) => write!(f, "{} — {} ({})", self.name, self.source, details),
+            None => write!(f, "{} — {}", self.name, self.source),
+        }
     }
 }
 
-pub async fn scrape_links(client: &reqwest::Client, url: &str) -> Result<HashMap<String, String>> {
-    let html = get_html(client, url).await?;
-    let links = filter_links(html);
-    Ok(links)
+pub async fn load_database(client: &reqwest::Client, config: &Config) -> Result<Vec<IndexEntry>> {
+    let mut database = match config.local_results_dir() {
+        Some(results_dir) if results_dir.join(&config.index).is_file() => {
+            let index_path = results_dir.join(&config.index);
+            let index = fs::read_to_string(&index_path)
+                .with_context(|| format!("Could not read {}.", index_path.display()))?;
+            parse_index(&index, &index_path.display().to_string())?
+        }
+        Some(results_dir) => {
+            let mut database = Vec::new();
+            index_local_results(
+                &results_dir,
+                &results_dir,
+                &config.parametric_eq,
+                &mut database,
+            )?;
+            database
+        }
+        None => {
+            let index_url = config.index_url()?;
+            parse_index(&fetch_text(client, config, &index_url).await?, &index_url)?
+        }
+    };
+    database.sort_by_key(|entry| (entry.name.to_lowercase(), entry.source.to_lowercase()));
+    Ok(database)
 }
 
-async fn get_html(client: &reqwest::Client, url: &str) -> Result<Html> {
-    let raw_result = client.get(url).send().await?.text().await?;
-    let html = Html::parse_document(&raw_result);
-    Ok(html)
+// every directory containing a ParametricEQ.txt is a result for one headphone
+fn index_local_results(
+    dir: &Path,
+    results_dir: &Path,
+    eq_file: &str,
+    database: &mut Vec<IndexEntry>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Could not read directory {}.", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            index_local_results(&path, results_dir, eq_file, database)?;
+            continue;
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !file_name.ends_with(&format!(" {}", eq_file)) {
+            continue;
+        }
+        if let Ok(relative_dir) = dir.strip_prefix(results_dir) {
+            let segments: Vec<String> = relative_dir
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let name = segments.last().cloned().unwrap_or_default();
+            database.push(IndexEntry::from_path(name, segments.join("/")));
+        }
+    }
+    Ok(())
 }
 
-fn filter_links(html: Html) -> HashMap<String, String> {
-    let mut link_list: HashMap<String, String> = HashMap::new();
-    let select_a = Selector::parse("a").unwrap();
+// e.g. "- [Sennheiser HD 650](./oratory1990/over-ear/Sennheiser%20HD%20650) by oratory1990"
+// or "- [1MORE Aero](./crinacle/GRAS%2043AG-7/1MORE%20Aero) by crinacle on GRAS 43AG-7"
+pub fn parse_index(index: &str, file: &str) -> Result<Vec<IndexEntry>, ScrapingError> {
+    let database: Vec<IndexEntry> = index.lines().filter_map(parse_index_line).collect();
+    if database.is_empty() {
+        return Err(ScrapingError::EmptyFile {
+            file: file.to_string(),
+        });
+    }
+    Ok(database)
+}
 
-    for link in html.select(&select_a) {
-        if let Some(url) = link.value().attr("href") {
-            let link_text = link
-                .inner_html()
-                .to_lowercase()
-                .trim()
-                .replace("&amp;", "&")
-                .to_string();
-            let link_url = url.trim().replace(['\\', '\"'], "").to_string();
-            if !link_text.len() > 100
-                && !link_text.contains('<')
-                && !link_text.contains('>')
-                && link_url != "#"
-            {
-                link_list.insert(link_text, link_url);
+fn parse_index_line(line: &str) -> Option<IndexEntry> {
+    let (name, rest) = line.trim().strip_prefix("- [")?.split_once("](")?;
+    let (path, attribution) = rest.split_once(')')?;
+    let path = percent_decode(path.trim_start_matches("./").trim_end_matches('/'));
+    let mut entry = IndexEntry::from_path(name.trim().to_string(), path);
+    if let Some(attribution) = attribution.trim().strip_prefix("by ") {
+        // the headphone type only comes from the path
+        let source = match attribution.split_once(" on ") {
+            Some((source, rig)) => {
+                entry.rig = Some(rig.trim().to_string());
+                source
             }
+            None => attribution,
+        };
+        entry.source = source.trim().to_string();
+    }
+    Some(entry)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => text
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
         };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// cached files are used until they expire or as a fallback when the network or server fails
+async fn fetch_text(client: &reqwest::Client, config: &Config, url: &str) -> Result<String> {
+    let cached = config.cache.load(url);
+    if let Some(entry) = &cached {
+        if entry.fresh && !config.cache.refresh() {
+            return Ok(entry.content.clone());
+        }
+    }
+    match get_text(client, url).await {
+        Ok(text) => {
+            if let Err(error) = config.cache.store(url, &text) {
+                config.warnings.push(error.to_string());
+            }
+            Ok(text)
+        }
+        Err(error) => {
+            // a missing file stays missing, but server errors are as temporary as network errors
+            let temporary = match error.downcast_ref::<ScrapingError>() {
+                None => true,
+                Some(ScrapingError::HttpStatus { status, .. }) => status.is_server_error(),
+                Some(_) => false,
+            };
+            match cached {
+                Some(entry) if temporary => {
+                    config.warnings.push(format!(
+                        "{} could not be loaded, using cached data instead.",
+                        url
+                    ));
+                    Ok(entry.content)
+                }
+                _ => Err(error),
+            }
+        }
     }
-    link_list
 }
 
-pub fn filter_link_list(link_list: &HashMap<String, String>, query: &str) -> Option<Link> {
-    link_list
-        .get(&query.to_lowercase())
-        .map(|url| Link::new(query.to_string(), url.to_string()))
+pub fn find_index_entry<'a>(
+    database: &'a [IndexEntry],
+    name: &str,
+    source: Option<&str>,
+    rig: Option<&str>,
+) -> Result<&'a IndexEntry, ScrapingError> {
+    let entries: Vec<&IndexEntry> = database
+        .iter()
+        .filter(|entry| entry.name.eq_ignore_ascii_case(name.trim()))
+        .filter(|entry| match source {
+            Some(source) => entry.source.eq_ignore_ascii_case(source.trim()),
+            None => true,
+        })
+        .filter(|entry| match rig {
+            Some(rig) => entry.matches_rig(rig),
+            None => true,
+        })
+        .collect();
+    match entries.as_slice() {
+        [entry] => Ok(entry),
+        [] => Err(ScrapingError::HeadphoneNotFound {
+            name: name.to_string(),
+        }),
+        entries => Err(ScrapingError::AmbiguousHeadphone {
+            name: name.to_string(),
+            sources: entries
+                .iter()
+                .map(|entry| match entry.details() {
+                    Some(details) => format!("{} ({})", entry.source, details),
+                    None => entry.source.clone(),
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+        }),
+    }
+}
+
+// fuzzy matches on the name, best matches first
+pub fn search_index<'a>(
+    database: &'a [IndexEntry],
+    query: &str,
+    source: Option<&str>,
+    rig: Option<&str>,
+) -> Vec<&'a IndexEntry> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let mut results: Vec<(i64, &IndexEntry)> = database
+        .iter()
+        .filter(|entry| match source {
+            Some(source) => entry.source.eq_ignore_ascii_case(source.trim()),
+            None => true,
+        })
+        .filter(|entry| match rig {
+            Some(rig) => entry.matches_rig(rig),
+            None => true,
+        })
+        .filter_map(|entry| {
+            matcher
+                .fuzzy_match(&entry.name, query.trim())
+                .map(|score| (score, entry))
+        })
+        .collect();
+    // the database is already sorted by name which keeps equal scores in order
+    results.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    results.into_iter().map(|(_, entry)| entry).collect()
 }
 
-pub async fn scrape_eq_settings(
-    url: &str,
+async fn get_text(client: &reqwest::Client, url: &str) -> Result<String> {
+    let response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(ScrapingError::HttpStatus {
+            url: url.to_string(),
+            status: response.status(),
+        }
+        .into());
+    }
+    Ok(response.text().await?)
+}
+
+pub async fn scrape_correction(
     client: &reqwest::Client,
-) -> Result<CorrectionFilterSet> {
-    let eq_file = client.get(url).send().await?.text().await?;
-    let mut data = eq_file.lines();
-    let preamp_gain = parse_preamp_gain(&mut data)?;
-    let mut filterset = CorrectionFilterSet::new(preamp_gain);
-    parse_filters(&mut data, &mut filterset)?;
+    config: &Config,
+    link: &str,
+    eq_source: EqSource,
+    fir: &FirSettings,
+) -> Result<Correction> {
+    let (eq_file, file) = match config.headphone_path(link, eq_source) {
+        Some(path) => (
+            fs::read_to_string(&path)
+                .with_context(|| format!("Could not read the EQ file {}.", path.display()))?,
+            path.display().to_string(),
+        ),
+        None => {
+            let url = config.headphone_url(link, eq_source)?;
+            (fetch_text(client, config, &url).await?, url)
+        }
+    };
+    let correction = match eq_source {
+        EqSource::Parametric | EqSource::FixedBand => {
+            Correction::Parametric(parse_eq_file(&eq_file, &file)?)
+        }
+        EqSource::Graphic => {
+            Correction::Graphic(parse_graphic_eq_file(&eq_file, &file)?, fir.clone())
+        }
+    };
+    Ok(correction)
+}
+
+pub fn read_correction(path: &Path, fir: &FirSettings) -> Result<Correction> {
+    let eq_file = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the EQ file {}.", path.display()))?;
+    let file = path.display().to_string();
+    let correction = match is_graphic_eq(&eq_file) {
+        true => Correction::Graphic(parse_graphic_eq_file(&eq_file, &file)?, fir.clone()),
+        false => Correction::Parametric(parse_eq_file(&eq_file, &file)?),
+    };
+    Ok(correction)
+}
+
+// e.g. "Sennheiser HD 650 ParametricEQ.txt" becomes "Sennheiser HD 650"
+pub fn headphone_name_from_path(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    ["ParametricEQ", "FixedBandEQ", "GraphicEQ"]
+        .iter()
+        .find_map(|suffix| stem.strip_suffix(suffix))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(stem)
+}
+
+pub fn is_graphic_eq(eq_file: &str) -> bool {
+    eq_file
+        .lines()
+        .any(|line| line.trim_start().starts_with("GraphicEQ:"))
+}
+
+// handles AutoEq ParametricEQ.txt and FixedBandEQ.txt files as well as Equalizer APO configs
+pub fn parse_eq_file(eq_file: &str, file: &str) -> Result<CorrectionFilterSet, ScrapingError> {
+    let mut filterset = CorrectionFilterSet::new(0.0);
+    let mut empty = true;
+    for (i, line) in eq_file.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        empty = false;
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        match keyword.to_ascii_lowercase().as_str() {
+            // Equalizer APO applies every preamp line
+            "preamp:" => filterset.gain += parse_preamp_gain(line, file, line_number)?,
+            "filter" | "filter:" => {
+                if let Some(eq) = parse_filter_line(line, file, line_number)? {
+                    filterset.eq_bands.push(eq);
+                }
+            }
+            // the correction is the same for both channels
+            "channel:" => {
+                let mut channels: Vec<String> = line
+                    .split_whitespace()
+                    .skip(1)
+                    .map(|channel| channel.to_ascii_lowercase())
+                    .collect();
+                channels.sort();
+                if channels != ["all"] && channels != ["l", "r"] && channels != ["1", "2"] {
+                    return Err(malformed_band(
+                        file,
+                        line_number,
+                        keyword,
+                        "filters for single channels are not supported",
+                    ));
+                }
+            }
+            // other Equalizer APO commands like 'Device:'
+            _ if keyword.ends_with(':') => (),
+            _ => {
+                return Err(malformed_band(
+                    file,
+                    line_number,
+                    keyword,
+                    "expected a preamp or filter line",
+                ))
+            }
+        }
+    }
+    if empty {
+        return Err(ScrapingError::EmptyFile {
+            file: file.to_string(),
+        });
+    }
     Ok(filterset)
 }
 
-pub fn parse_preamp_gain(lines: &mut std::str::Lines) -> Result<f32> {
-    let gain = lines
-        .next()
-        .ok_or_else(|| anyhow!("Not enough lines."))?
-        .split(' ')
-        .nth(1)
-        .ok_or_else(|| anyhow!("Not enough elements."))?
-        .parse::<f32>()?;
-    Ok(gain)
+fn parse_preamp_gain(line: &str, file: &str, line_number: usize) -> Result<f32, ScrapingError> {
+    let value = line.split_whitespace().nth(1).unwrap_or_default();
+    value
+        .parse::<f32>()
+        .map_err(|_| ScrapingError::MalformedPreamp {
+            file: file.to_string(),
+            line: line_number,
+            token: value.to_string(),
+        })
 }
 
-pub fn parse_filters(
-    lines: &mut std::str::Lines,
-    filterset: &mut CorrectionFilterSet,
-) -> Result<()> {
-    for line in lines.skip(0) {
-        let eq = parse_filter_line(line)?;
-        filterset.eq_bands.push(eq);
+#[derive(Debug, Default)]
+struct FilterLine<'a> {
+    enabled: bool,
+    filter_type: &'a str,
+    slope: Option<f32>,
+    freq: Option<f32>,
+    gain: Option<f32>,
+    q: Option<f32>,
+}
+
+fn malformed_band(file: &str, line: usize, token: &str, reason: &str) -> ScrapingError {
+    ScrapingError::MalformedBand {
+        file: file.to_string(),
+        line,
+        token: token.to_string(),
+        reason: reason.to_string(),
     }
-    Ok(())
 }
 
-fn parse_filter_line(line: &str) -> Result<BiquadParameters> {
-    let mut split_line = line.split(' ');
-    let fc = split_line.nth(5);
-    let gain = split_line.nth(2);
-    let q = split_line.nth(2);
-    match (fc, gain, q) {
-        (Some(fc), Some(gain), Some(q)) => {
-            let freq: f32 = fc.parse()?;
-            let gain: f32 = gain.parse()?;
-            let q: f32 = q.parse()?;
-            let eq = BiquadParameters::Peaking(PeakingWidth::Q { freq, q, gain });
-            Ok(eq)
-        }
-        _ => panic!("The value could not be found."),
+// e.g. "Filter 1: ON LSC Fc 105 Hz Gain 6.6 dB Q 0.70", keywords are matched ignoring case
+fn tokenize_filter_line<'a>(
+    line: &'a str,
+    file: &str,
+    line_number: usize,
+) -> Result<FilterLine<'a>, ScrapingError> {
+    let mut tokens = line
+        .split_whitespace()
+        .skip_while(|token| !token.ends_with(':'))
+        .skip(1);
+    let state = tokens.next().unwrap_or_default();
+    let mut filter_line = FilterLine {
+        enabled: match state.to_ascii_lowercase().as_str() {
+            "on" => true,
+            "off" => false,
+            _ => {
+                return Err(malformed_band(
+                    file,
+                    line_number,
+                    state,
+                    "expected filter state ON or OFF",
+                ))
+            }
+        },
+        filter_type: tokens
+            .next()
+            .ok_or_else(|| malformed_band(file, line_number, "", "expected filter type"))?,
+        ..Default::default()
+    };
+    while let Some(token) = tokens.next() {
+        let keyword = token.to_ascii_lowercase();
+        let value = match keyword.as_str() {
+            "fc" => &mut filter_line.freq,
+            "gain" => &mut filter_line.gain,
+            "q" => &mut filter_line.q,
+            // units following a value
+            "hz" | "db" => continue,
+            // optional shelf slope e.g. "LS 6dB" or "LS 6 dB"
+            _ => match keyword.trim_end_matches("db").parse::<f32>() {
+                Ok(slope) if filter_line.freq.is_none() => {
+                    filter_line.slope = Some(slope);
+                    continue;
+                }
+                _ => return Err(malformed_band(file, line_number, token, "unexpected token")),
+            },
+        };
+        let number = tokens.next().unwrap_or_default();
+        *value = Some(number.parse::<f32>().map_err(|_| {
+            malformed_band(
+                file,
+                line_number,
+                number,
+                &format!("expected a number for '{}'", token),
+            )
+        })?);
+    }
+    Ok(filter_line)
+}
+
+fn parse_filter_line(
+    line: &str,
+    file: &str,
+    line_number: usize,
+) -> Result<Option<BiquadParameters>, ScrapingError> {
+    let filter_line = tokenize_filter_line(line, file, line_number)?;
+    if !filter_line.enabled {
+        return Ok(None);
+    }
+    let missing = |parameter: &str| {
+        malformed_band(
+            file,
+            line_number,
+            filter_line.filter_type,
+            &format!("missing the {} value", parameter),
+        )
+    };
+    let freq = filter_line.freq.ok_or_else(|| missing("Fc"))?;
+
+    let eq = match filter_line.filter_type.to_ascii_uppercase().as_str() {
+        "PK" | "PEQ" => BiquadParameters::Peaking(PeakingWidth::Q {
+            freq,
+            q: filter_line.q.ok_or_else(|| missing("Q"))?,
+            gain: filter_line.gain.ok_or_else(|| missing("Gain"))?,
+        }),
+        "LSC" | "LS" => BiquadParameters::Lowshelf(shelf_steepness(
+            &filter_line,
+            freq,
+            filter_line.gain.ok_or_else(|| missing("Gain"))?,
+        )),
+        "HSC" | "HS" => BiquadParameters::Highshelf(shelf_steepness(
+            &filter_line,
+            freq,
+            filter_line.gain.ok_or_else(|| missing("Gain"))?,
+        )),
+        "HP" | "HPQ" => BiquadParameters::Highpass {
+            freq,
+            q: filter_line.q.unwrap_or(BUTTERWORTH_Q),
+        },
+        "LP" | "LPQ" => BiquadParameters::Lowpass {
+            freq,
+            q: filter_line.q.unwrap_or(BUTTERWORTH_Q),
+        },
+        _ => {
+            return Err(ScrapingError::UnsupportedFilterType {
+                file: file.to_string(),
+                line: line_number,
+                filter_type: filter_line.filter_type.to_string(),
+            })
+        }
+    };
+    Ok(Some(eq))
+}
+
+const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+// Equalizer APO uses a slope of 12 dB/octave for shelf filters without Q or slope
+const DEFAULT_SHELF_SLOPE: f32 = 12.0;
+
+fn shelf_steepness(filter_line: &FilterLine, freq: f32, gain: f32) -> ShelfSteepness {
+    match (filter_line.q, filter_line.slope) {
+        (Some(q), _) => ShelfSteepness::Q { freq, q, gain },
+        (None, Some(slope)) => ShelfSteepness::Slope { freq, slope, gain },
+        (None, None) => ShelfSteepness::Slope {
+            freq,
+            slope: DEFAULT_SHELF_SLOPE,
+            gain,
+        },
+    }
+}
+
+// e.g. "GraphicEQ: 20 -6.2; 21 -6.3; 22 -6.4"
+pub fn parse_graphic_eq_file(eq_file: &str, file: &str) -> Result<GraphicEq, ScrapingError> {
+    let mut graphic_eq = GraphicEq {
+        gain: 0.0,
+        points: Vec::new(),
+    };
+    for (i, line) in eq_file.lines().enumerate() {
+        let line_number = i + 1;
+        let Some((keyword, values)) = line.split_once(':') else {
+            continue;
+        };
+        match keyword.trim().to_ascii_lowercase().as_str() {
+            "preamp" => {
+                let value = values.split_whitespace().next().unwrap_or_default();
+                graphic_eq.gain +=
+                    value
+                        .parse::<f32>()
+                        .map_err(|_| ScrapingError::MalformedPreamp {
+                            file: file.to_string(),
+                            line: line_number,
+                            token: value.to_string(),
+                        })?;
+            }
+            "graphiceq" => {
+                for point in values.split(';').filter(|point| !point.trim().is_empty()) {
+                    let malformed = || ScrapingError::MalformedPoint {
+                        file: file.to_string(),
+                        line: line_number,
+                        token: point.trim().to_string(),
+                    };
+                    let mut tokens = point.split_whitespace().map(str::parse::<f32>);
+                    match (tokens.next(), tokens.next(), tokens.next()) {
+                        (Some(Ok(freq)), Some(Ok(gain)), None) => {
+                            graphic_eq.points.push(GraphicEqPoint { freq, gain })
+                        }
+                        _ => return Err(malformed()),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if graphic_eq.points.is_empty() {
+        return Err(ScrapingError::EmptyFile {
+            file: file.to_string(),
+        });
+    }
+    Ok(graphic_eq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noninteractive::GlobalArgs;
+    use crate::report::Warnings;
+    use crate::ConfigOverrides;
+    use std::path::PathBuf;
+
+    fn fixture_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/AutoEq")
+    }
+
+    fn fixture_config() -> Config {
+        let args = GlobalArgs {
+            autoeq_dir: Some(fixture_dir()),
+            cache_ttl: 24,
+            refresh: false,
+            config: None,
+            crossfeed_dir: None,
+            overrides: ConfigOverrides::default(),
+        };
+        Config::load(args, Warnings::default()).unwrap()
+    }
+
+    #[test]
+    fn local_results_are_indexed() {
+        let results_dir = fixture_dir().join("results");
+        let mut database = Vec::new();
+        index_local_results(
+            &results_dir,
+            &results_dir,
+            "ParametricEQ.txt",
+            &mut database,
+        )
+        .unwrap();
+        database.sort_by(|a, b| a.source.cmp(&b.source));
+
+        // the result without a ParametricEQ.txt is skipped
+        assert_eq!(database.len(), 2);
+        assert_eq!(database[0].name, "Sennheiser HD 650");
+        assert_eq!(database[0].source, "crinacle");
+        assert_eq!(database[0].rig.as_deref(), Some("GRAS 43AG-7"));
+        assert_eq!(database[0].headphone_type.as_deref(), Some("over-ear"));
+        assert_eq!(
+            database[0].path,
+            "crinacle/GRAS 43AG-7 over-ear/Sennheiser HD 650"
+        );
+        assert_eq!(database[1].source, "oratory1990");
+        assert_eq!(database[1].rig, None);
+        assert_eq!(database[1].headphone_type.as_deref(), Some("over-ear"));
+    }
+
+    #[tokio::test]
+    async fn local_corrections_are_loaded() {
+        let client = reqwest::Client::new();
+        let config = fixture_config();
+        let database = load_database(&client, &config).await.unwrap();
+        let entry =
+            find_index_entry(&database, "sennheiser hd 650", Some("crinacle"), None).unwrap();
+
+        match scrape_correction(
+            &client,
+            &config,
+            &entry.path,
+            EqSource::Parametric,
+            &FirSettings::default(),
+        )
+        .await
+        .unwrap()
+        {
+            Correction::Parametric(filterset) => {
+                assert_eq!(filterset.gain, -5.0);
+                assert_eq!(filterset.eq_bands.len(), 1);
+            }
+            correction => panic!("unexpected correction {:?}", correction),
+        }
+        match scrape_correction(
+            &client,
+            &config,
+            &entry.path,
+            EqSource::Graphic,
+            &FirSettings::default(),
+        )
+        .await
+        .unwrap()
+        {
+            Correction::Graphic(graphic_eq, _) => assert_eq!(graphic_eq.points.len(), 3),
+            correction => panic!("unexpected correction {:?}", correction),
+        }
+    }
+
+    fn filter(line: &str) -> Result<Option<BiquadParameters>, ScrapingError> {
+        parse_filter_line(line, "ParametricEQ.txt", 3)
+    }
+
+    #[test]
+    fn shelf_filters_take_an_optional_slope() {
+        let shelf = filter("Filter 1: ON LSC Fc 105 Hz Gain 6.6 dB").unwrap();
+        assert!(matches!(
+            shelf,
+            Some(BiquadParameters::Lowshelf(ShelfSteepness::Slope { freq, slope, gain }))
+                if freq == 105.0 && slope == 12.0 && gain == 6.6
+        ));
+        let shelf = filter("Filter 2: ON HSC 6dB Fc 8000 Hz Gain -3 dB").unwrap();
+        assert!(matches!(
+            shelf,
+            Some(BiquadParameters::Highshelf(ShelfSteepness::Slope { slope, gain, .. }))
+                if slope == 6.0 && gain == -3.0
+        ));
+        let shelf = filter("Filter 3: ON HSC 6 dB Fc 8000 Hz Gain -3 dB").unwrap();
+        assert!(matches!(
+            shelf,
+            Some(BiquadParameters::Highshelf(ShelfSteepness::Slope { slope, .. })) if slope == 6.0
+        ));
+        let shelf = filter("Filter 4: ON LSC Fc 105 Hz Gain 6.6 dB Q 0.7").unwrap();
+        assert!(matches!(
+            shelf,
+            Some(BiquadParameters::Lowshelf(ShelfSteepness::Q { q, .. })) if q == 0.7
+        ));
+    }
+
+    #[test]
+    fn filter_keywords_ignore_case() {
+        let peak = filter("filter 1: on pk fc 1000 hz gain -2 db q 1.41").unwrap();
+        assert!(matches!(
+            peak,
+            Some(BiquadParameters::Peaking(PeakingWidth::Q { freq, q, gain }))
+                if freq == 1000.0 && q == 1.41 && gain == -2.0
+        ));
+    }
+
+    #[test]
+    fn disabled_filters_are_skipped() {
+        assert!(filter("Filter 1: OFF PK Fc 1000 Hz Gain -2 dB Q 1.41")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn a_missing_number_is_reported() {
+        let error = filter("Filter 1: ON PK Fc 1000 Hz Gain -2 dB Q").unwrap_err();
+        assert!(matches!(
+            error,
+            ScrapingError::MalformedBand { line: 3, ref reason, .. } if reason.contains("'Q'")
+        ));
+    }
+
+    #[test]
+    fn unknown_filter_types_are_reported_with_their_line() {
+        let error = parse_eq_file(
+            "Preamp: -3 dB\nFilter 1: ON NO Fc 1000 Hz",
+            "ParametricEQ.txt",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            ScrapingError::UnsupportedFilterType { line: 2, ref filter_type, .. }
+                if filter_type == "NO"
+        ));
+    }
+
+    #[test]
+    fn crinacle_index_lines_have_a_rig_and_type() {
+        let entry = parse_index_line(
+            "- [Sennheiser HD 650](./crinacle/GRAS%2043AG-7%20over-ear/Sennheiser%20HD%20650) by crinacle on GRAS 43AG-7",
+        )
+        .unwrap();
+        assert_eq!(entry.name, "Sennheiser HD 650");
+        assert_eq!(entry.source, "crinacle");
+        assert_eq!(entry.rig.as_deref(), Some("GRAS 43AG-7"));
+        assert_eq!(entry.headphone_type.as_deref(), Some("over-ear"));
+        assert_eq!(
+            entry.path,
+            "crinacle/GRAS 43AG-7 over-ear/Sennheiser HD 650"
+        );
+    }
+
+    #[test]
+    fn oratory1990_index_lines_have_a_type() {
+        let entry = parse_index_line(
+            "- [Sennheiser HD 650](./oratory1990/over-ear/Sennheiser%20HD%20650) by oratory1990",
+        )
+        .unwrap();
+        assert_eq!(entry.name, "Sennheiser HD 650");
+        assert_eq!(entry.source, "oratory1990");
+        assert_eq!(entry.rig, None);
+        assert_eq!(entry.headphone_type.as_deref(), Some("over-ear"));
+        assert_eq!(entry.path, "oratory1990/over-ear/Sennheiser HD 650");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(percent_decode("100%25%20Audio"), "100% Audio");
+        assert_eq!(percent_decode("100%zz"), "100%zz");
+        assert_eq!(percent_decode("100%2"), "100%2");
+    }
+
+    #[test]
+    fn index_lines_without_a_link_are_skipped() {
+        assert!(parse_index_line("# Headphones").is_none());
+        assert!(parse_index_line("- Sennheiser HD 650 by oratory1990").is_none());
+        assert!(matches!(
+            parse_index("# Headphones\n", "INDEX.md"),
+            Err(ScrapingError::EmptyFile { .. })
+        ));
     }
 }
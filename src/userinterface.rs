@@ -1,12 +1,29 @@
 use crate::{
-    configcreation::{Crossfeed, DevicesFile},
-    scraping::filter_link_list,
+    configcreation::CamillaDspVersion,
+    crossfeed::{
+        Crossfeed, CrossfeedPresets, Hrir, HrirFiles, CROSSFEED_FCUT, CROSSFEED_FCUT_DEFAULT,
+        CROSSFEED_FEED, CROSSFEED_FEED_DEFAULT,
+    },
+    devices::{
+        alsa_devices, save_devices, AlsaDevice, Backend, Device, Devices, DevicesFile, Resampler,
+        SampleFormat, SincProfile,
+    },
+    fir::{FirPhase, FirSettings},
+    scraping::{headphone_name_from_path, is_graphic_eq, EqSource, IndexEntry},
 };
 
 use anyhow::{anyhow, Result};
 use console::{style, Style};
 use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input, Select};
-use std::{collections::HashMap, env, fs::File, thread, time::Duration};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
 pub trait CliTheme {
     fn clitheme() -> Self;
@@ -43,16 +60,24 @@ impl CliTheme for ColorfulTheme {
 pub struct Cli {
     pub headphone: String,
     pub headphone_url: String,
+    pub eq_file: Option<PathBuf>,
+    pub eq_source: EqSource,
+    pub fir: FirSettings,
     pub devices: DevicesFile,
     pub crossfeed: Crossfeed,
+    pub camilladsp_version: CamillaDspVersion,
 }
 impl Cli {
     pub fn initialize() -> Self {
         Cli {
             headphone: String::new(),
             headphone_url: String::new(),
+            eq_file: None,
+            eq_source: EqSource::Parametric,
+            fir: FirSettings::default(),
             devices: DevicesFile::Default,
             crossfeed: Crossfeed::None,
+            camilladsp_version: CamillaDspVersion::default(),
         }
     }
 
@@ -83,8 +108,8 @@ impl Cli {
         thread::sleep(Duration::from_millis(2000));
     }
 
-    pub fn select_headphone(&mut self, database: &HashMap<String, String>) -> Result<()> {
-        let mut suggestions: Vec<String> = database.keys().cloned().collect();
+    pub fn select_headphone(&mut self, database: &[IndexEntry]) -> Result<()> {
+        let mut suggestions: Vec<String> = database.iter().map(|entry| entry.to_string()).collect();
         suggestions.push("Exit".to_string());
         println!();
         let selection = FuzzySelect::with_theme(&ColorfulTheme::clitheme())
@@ -95,11 +120,11 @@ impl Cli {
         if suggestions[selection] == "Exit" {
             std::process::exit(0);
         }
-        let headphone_link = filter_link_list(database, &suggestions[selection]);
-        match headphone_link {
-            Some(link) => {
-                self.headphone = link.name;
-                self.headphone_url = link.url;
+        let headphone_entry = database.get(selection);
+        match headphone_entry {
+            Some(entry) => {
+                self.headphone = entry.name.clone();
+                self.headphone_url = entry.path.clone();
                 println!();
                 Ok(())
             }
@@ -109,9 +134,111 @@ impl Cli {
         }
     }
 
+    pub fn query_eq_file(&mut self) -> Result<()> {
+        let items = vec![
+            "AutoEq database",
+            "Local EQ file (ParametricEQ.txt, GraphicEQ.txt or Equalizer APO config)",
+        ];
+        let eq_file_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Where would you like to load the correction from?")
+            .items(&items)
+            .default(0)
+            .interact()?;
+        if eq_file_query == 0 {
+            return Ok(());
+        }
+
+        let eq_file_path: String = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please enter the relative path to your EQ file:")
+            .validate_with(|path: &String| match Path::new(path).is_file() {
+                true => Ok(()),
+                false => Err("Sorry this file does not seem to exist."),
+            })
+            .interact_text()?;
+        let eq_file = PathBuf::from(eq_file_path);
+        self.headphone = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please enter the name of your device:")
+            .default(headphone_name_from_path(&eq_file))
+            .interact_text()?;
+        let graphic_eq = fs::read_to_string(&eq_file)
+            .map(|eq_file| is_graphic_eq(&eq_file))
+            .unwrap_or(false);
+        if graphic_eq {
+            self.eq_source = EqSource::Graphic;
+            self.query_fir_settings()?;
+        }
+        self.eq_file = Some(eq_file);
+        println!();
+        Ok(())
+    }
+
+    pub fn query_eq_source(&mut self) -> Result<()> {
+        let items = vec![
+            "Parametric EQ (ParametricEQ.txt)",
+            "Fixed band EQ (FixedBandEQ.txt)",
+            "Graphic EQ as FIR filter (GraphicEQ.txt)",
+        ];
+        let eq_source_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please select the AutoEq correction you would like to use:")
+            .items(&items)
+            .default(0)
+            .interact()?;
+
+        self.eq_source = match eq_source_query {
+            1 => EqSource::FixedBand,
+            2 => EqSource::Graphic,
+            _ => EqSource::Parametric,
+        };
+        if let EqSource::Graphic = self.eq_source {
+            self.query_fir_settings()?;
+        }
+        println!();
+        Ok(())
+    }
+
+    fn query_fir_settings(&mut self) -> Result<()> {
+        let fir_explainer: &str = r"
+The graphic EQ will be turned into an impulse response which is saved next to your configuration.
+Please make sure the sample rate matches the one of your 'devices' section.
+";
+        println!();
+        print!("{}", style(fir_explainer).magenta());
+        println!();
+
+        let phase_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please select the phase of the FIR filter:")
+            .items(&["Minimum phase", "Linear phase"])
+            .default(0)
+            .interact()?;
+        self.fir.phase = match phase_query {
+            1 => FirPhase::Linear,
+            _ => FirPhase::Minimum,
+        };
+        self.fir.taps = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Number of taps:")
+            .default(self.fir.taps)
+            .validate_with(|taps: &usize| match *taps >= 16 {
+                true => Ok(()),
+                false => Err("The FIR filter needs at least 16 taps."),
+            })
+            .interact_text()?;
+        // the sample rate is taken from the devices
+        Ok(())
+    }
+
+    pub fn confirm_overwrite(&self, path: &Path) -> Result<bool> {
+        let overwrite = Confirm::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt(format!(
+                "{} already exists. Would you like to overwrite it?",
+                path.display()
+            ))
+            .interact()?;
+        Ok(overwrite)
+    }
+
     pub fn query_custom_devices(&mut self) -> Result<()> {
         let custom_explainer: &str = r"
-You have the option to include a custom 'devices' section from a .yml file.
+You have the option to include a custom 'devices' section from a .yml file or to build one step by step.
 If you do not choose to do so, the configuration will be created with a default 'devices' section.
 You then can edit this and use for future configurations.
 ";
@@ -119,14 +246,21 @@ You then can edit this and use for future configurations.
         print!("{}", style(custom_explainer).magenta());
         println!();
 
-        let custom_devices_query: bool = Confirm::with_theme(&ColorfulTheme::clitheme())
-        .with_prompt(
-            "Would you like to include a custom 'devices' section for your CamillaDSP config file?",
-        )
-        .interact()?;
+        let devices_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt(
+                "Which 'devices' section would you like to use for your CamillaDSP config file?",
+            )
+            .items(&[
+                "Default 'devices' section",
+                "Build a 'devices' section step by step",
+                "Custom 'devices' section from a file",
+            ])
+            .default(0)
+            .interact()?;
 
-        self.devices = match custom_devices_query {
-            true => {
+        self.devices = match devices_query {
+            1 => DevicesFile::Built(Box::new(self.query_devices_wizard()?)),
+            2 => {
                 let mut custom_device_path: String = Input::with_theme(&ColorfulTheme::clitheme())
                     .with_prompt("Please enter the relative path to your custom 'devices' file:")
                     .interact_text()?;
@@ -146,20 +280,113 @@ Otherwise try again and enter the relative path to your custom 'devices' file:",
                 }
                 DevicesFile::Custom(custom_device_path)
             }
-            false => DevicesFile::Default,
+            _ => DevicesFile::Default,
         };
         println!();
         Ok(())
     }
 
-    pub fn query_crossfeed(&mut self) -> Result<()> {
+    fn query_devices_wizard(&self) -> Result<Devices> {
         println!();
-        let items = vec![
-            "None",
-            "Pow Chu Moy Crossfeed",
-            "MPM Crossfeed",
-            "Natural Crossfeed",
-        ];
+        let samplerates = [44100, 48000, 88200, 96000, 176400, 192000];
+        let samplerate_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please select the sample rate:")
+            .items(&samplerates)
+            .default(0)
+            .interact()?;
+        let samplerate = samplerates[samplerate_query];
+        let chunksize: usize = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Chunk size:")
+            .default(1024)
+            .validate_with(|chunksize: &usize| match *chunksize > 0 {
+                true => Ok(()),
+                false => Err("Please enter a valid chunk size."),
+            })
+            .interact_text()?;
+
+        let alsa_devices = alsa_devices();
+        let capture = query_device("capture", &alsa_devices)?;
+        let playback = query_device("playback", &alsa_devices)?;
+
+        let resampler_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please select a resampler:")
+            .items(&[
+                "None",
+                "Synchronous",
+                "Asynchronous, fast",
+                "Asynchronous, balanced",
+                "Asynchronous, accurate",
+            ])
+            .default(0)
+            .interact()?;
+        let profile = match resampler_query {
+            2 => Some(SincProfile::Fast),
+            3 => Some(SincProfile::Balanced),
+            4 => Some(SincProfile::Accurate),
+            _ => None,
+        };
+        let resampler = match (resampler_query, profile) {
+            (0, _) => None,
+            (_, None) => Some(Resampler::Synchronous),
+            (_, Some(profile)) => Some(Resampler::AsyncSinc {
+                profile: Some(profile),
+                parameters: BTreeMap::new(),
+            }),
+        };
+        let capture_samplerate = match resampler {
+            Some(_) => Some(
+                Input::with_theme(&ColorfulTheme::clitheme())
+                    .with_prompt("Sample rate of the capture device:")
+                    .default(samplerate)
+                    .interact_text()?,
+            ),
+            None => None,
+        };
+
+        let devices = Devices {
+            samplerate,
+            chunksize,
+            queuelimit: None,
+            capture_samplerate,
+            enable_resampling: None,
+            resampler_type: None,
+            resampler,
+            options: BTreeMap::new(),
+            capture,
+            playback,
+        };
+
+        let save_query = Confirm::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Would you like to save this 'devices' section for future configurations?")
+            .interact()?;
+        if save_query {
+            let path: String = Input::with_theme(&ColorfulTheme::clitheme())
+                .with_prompt("Please enter the relative path for your 'devices' file:")
+                .default("devices.yml".to_string())
+                .interact_text()?;
+            let path = PathBuf::from(path);
+            if !path.exists() || self.confirm_overwrite(&path)? {
+                save_devices(&devices, &path)?;
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn query_crossfeed(&mut self, presets: &CrossfeedPresets) -> Result<()> {
+        println!();
+        let items: Vec<&str> = ["None"]
+            .into_iter()
+            .chain(
+                presets
+                    .presets()
+                    .iter()
+                    .map(|preset| preset.description.as_str()),
+            )
+            .chain([
+                "Custom Crossfeed (bs2b)",
+                "HRIR Convolution (speaker simulation)",
+            ])
+            .collect();
         let crossfeed_query = Select::with_theme(&ColorfulTheme::clitheme())
         .with_prompt(
             "Please select the type of Crossfeed you would like to include in your configuration:"
@@ -170,12 +397,192 @@ Otherwise try again and enter the relative path to your custom 'devices' file:",
 
         self.crossfeed = match crossfeed_query {
             0 => Crossfeed::None,
-            1 => Crossfeed::PowChuMoy,
-            2 => Crossfeed::Mpm,
-            3 => Crossfeed::Natural,
-            _ => Crossfeed::None,
+            i if i <= presets.presets().len() => {
+                Crossfeed::Preset(presets.presets()[i - 1].clone())
+            }
+            i if i == presets.presets().len() + 1 => self.query_custom_crossfeed()?,
+            _ => self.query_hrir_crossfeed()?,
         };
         println!();
         Ok(())
     }
+
+    fn query_custom_crossfeed(&self) -> Result<Crossfeed> {
+        let fcut = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt(format!(
+                "Cutoff frequency in Hz ({} to {}):",
+                CROSSFEED_FCUT.start(),
+                CROSSFEED_FCUT.end()
+            ))
+            .default(CROSSFEED_FCUT_DEFAULT)
+            .validate_with(|fcut: &f32| match CROSSFEED_FCUT.contains(fcut) {
+                true => Ok(()),
+                false => Err("The cutoff frequency is out of range."),
+            })
+            .interact_text()?;
+        let feed = Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt(format!(
+                "Feed level in dB ({} to {}):",
+                CROSSFEED_FEED.start(),
+                CROSSFEED_FEED.end()
+            ))
+            .default(CROSSFEED_FEED_DEFAULT)
+            .validate_with(|feed: &f32| match CROSSFEED_FEED.contains(feed) {
+                true => Ok(()),
+                false => Err("The feed level is out of range."),
+            })
+            .interact_text()?;
+        Ok(Crossfeed::Custom { fcut, feed })
+    }
+
+    fn query_hrir_crossfeed(&self) -> Result<Crossfeed> {
+        let layout = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("How are your head related impulse responses stored?")
+            .items(&[
+                "Two mono WAV files (ipsilateral and contralateral)",
+                "One WAV file with four channels (stereo speakers)",
+            ])
+            .default(0)
+            .interact()?;
+        let query_path = |prompt: &str| -> Result<PathBuf> {
+            let path: String = Input::with_theme(&ColorfulTheme::clitheme())
+                .with_prompt(prompt)
+                .validate_with(|path: &String| match Path::new(path).is_file() {
+                    true => Ok(()),
+                    false => Err("The file does not exist."),
+                })
+                .interact_text()?;
+            Ok(PathBuf::from(path))
+        };
+        let files = match layout {
+            0 => HrirFiles::Pair {
+                ipsilateral: query_path("Path of the ipsilateral (same side) impulse response:")?,
+                contralateral: query_path(
+                    "Path of the contralateral (opposite side) impulse response:",
+                )?,
+            },
+            _ => HrirFiles::Speakers {
+                speakers: query_path("Path of the impulse responses of both speakers:")?,
+            },
+        };
+        Ok(Crossfeed::Hrir(Arc::new(Hrir::load(&files)?)))
+    }
+
+    pub fn query_camilladsp_version(&mut self) -> Result<()> {
+        let items = vec!["CamillaDSP 3.x", "CamillaDSP 2.x", "CamillaDSP 1.x"];
+        let version_query = Select::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt("Please select the version of CamillaDSP you are using:")
+            .items(&items)
+            .default(0)
+            .interact()?;
+
+        self.camilladsp_version = match version_query {
+            1 => CamillaDspVersion::V2,
+            2 => CamillaDspVersion::V1,
+            _ => CamillaDspVersion::V3,
+        };
+        println!();
+        Ok(())
+    }
+}
+
+fn query_device(direction: &str, alsa_devices: &[AlsaDevice]) -> Result<Device> {
+    println!();
+    let backends = match direction {
+        "capture" => [
+            Backend::Alsa,
+            Backend::Pulse,
+            Backend::Jack,
+            Backend::File,
+            Backend::Stdin,
+        ],
+        _ => [
+            Backend::Alsa,
+            Backend::Pulse,
+            Backend::Jack,
+            Backend::File,
+            Backend::Stdout,
+        ],
+    };
+    let backend_query = Select::with_theme(&ColorfulTheme::clitheme())
+        .with_prompt(format!("Please select the {} backend:", direction))
+        .items(&backends)
+        .default(0)
+        .interact()?;
+    let backend = backends[backend_query];
+
+    let device = match backend {
+        Backend::Alsa => Some(query_alsa_device(direction, alsa_devices)?),
+        Backend::Pulse | Backend::Jack => Some(
+            Input::with_theme(&ColorfulTheme::clitheme())
+                .with_prompt(format!("Name of the {} device:", direction))
+                .default("default".to_string())
+                .interact_text()?,
+        ),
+        _ => None,
+    };
+    let filename = match backend {
+        Backend::File => Some(
+            Input::with_theme(&ColorfulTheme::clitheme())
+                .with_prompt(format!("Path of the {} file:", direction))
+                .interact_text()?,
+        ),
+        _ => None,
+    };
+    let format = match backend {
+        Backend::Jack => None,
+        _ => {
+            let formats = [
+                ("S16LE", SampleFormat::S16Le),
+                ("S24LE", SampleFormat::S24Le),
+                ("S24LE3", SampleFormat::S24Le3),
+                ("S32LE", SampleFormat::S32Le),
+                ("FLOAT32LE", SampleFormat::Float32Le),
+                ("FLOAT64LE", SampleFormat::Float64Le),
+            ];
+            let format_query = Select::with_theme(&ColorfulTheme::clitheme())
+                .with_prompt(format!("Please select the {} sample format:", direction))
+                .items(&formats.map(|(name, _)| name))
+                .default(0)
+                .interact()?;
+            Some(formats[format_query].1)
+        }
+    };
+
+    Ok(Device {
+        r#type: backend,
+        channels: Some(2),
+        device,
+        filename,
+        format,
+        options: BTreeMap::new(),
+    })
+}
+
+fn query_alsa_device(direction: &str, alsa_devices: &[AlsaDevice]) -> Result<String> {
+    let alsa_devices: Vec<&AlsaDevice> = alsa_devices
+        .iter()
+        .filter(|device| match direction {
+            "capture" => device.capture,
+            _ => device.playback,
+        })
+        .collect();
+    let mut items: Vec<String> = alsa_devices
+        .iter()
+        .map(|device| device.to_string())
+        .collect();
+    items.push("Enter a device name".to_string());
+    let device_query = Select::with_theme(&ColorfulTheme::clitheme())
+        .with_prompt(format!("Please select the ALSA {} device:", direction))
+        .items(&items)
+        .default(0)
+        .interact()?;
+    let device = match alsa_devices.get(device_query) {
+        Some(device) => device.hw_name(),
+        None => Input::with_theme(&ColorfulTheme::clitheme())
+            .with_prompt(format!("Name of the ALSA {} device:", direction))
+            .default("hw:0,0".to_string())
+            .interact_text()?,
+    };
+    Ok(device)
 }
@@ -0,0 +1,345 @@
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+use thiserror::Error;
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::configcreation::Configuration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+// time for CamillaDSP to open the devices with the new configuration
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Error)]
+pub enum ApplyError {
+    #[error("could not connect to CamillaDSP at {url}: {reason}")]
+    Connection { url: String, reason: String },
+    #[error("CamillaDSP did not answer {command}: {reason}")]
+    Protocol { command: String, reason: String },
+    #[error("CamillaDSP rejected the configuration: {message}")]
+    Rejected { message: String },
+    #[error("the current configuration could not be read from CamillaDSP to restore it later: {reason}, add --no-rollback to apply without restoring it")]
+    NoRollback { reason: String },
+    #[error("CamillaDSP stopped with the new configuration: {reason}")]
+    Failed { reason: String },
+    #[error("CamillaDSP stopped with the new configuration: {reason}, the previous configuration was restored")]
+    RolledBack { reason: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApplyReport {
+    pub url: String,
+    // answer of CamillaDSP to the validation
+    pub validation: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply {
+    result: String,
+    #[serde(default)]
+    value: Option<Value>,
+}
+impl Reply {
+    fn is_ok(&self) -> bool {
+        self.result == "Ok"
+    }
+    // errors are described in the value, configs and states are sent as strings
+    fn message(&self) -> String {
+        match &self.value {
+            Some(Value::String(value)) => value.clone(),
+            // stop reasons like {"CaptureError": "..."}
+            Some(Value::Object(value)) if value.len() == 1 => value
+                .iter()
+                .map(|(reason, message)| match message.as_str() {
+                    Some(message) => format!("{}: {}", reason, message),
+                    None => format!("{}: {}", reason, message),
+                })
+                .collect(),
+            Some(value) => value.to_string(),
+            None => self.result.clone(),
+        }
+    }
+}
+
+struct CamillaDsp {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+impl CamillaDsp {
+    async fn connect(url: &str) -> Result<Self, ApplyError> {
+        let connection = |reason: String| ApplyError::Connection {
+            url: url.to_string(),
+            reason,
+        };
+        let (socket, _) = timeout(TIMEOUT, connect_async(url))
+            .await
+            .map_err(|_| connection("timed out".to_string()))?
+            .map_err(|error| connection(error.to_string()))?;
+        Ok(CamillaDsp { socket })
+    }
+
+    // commands without an argument are sent as a plain string
+    async fn request(
+        &mut self,
+        command: &str,
+        argument: Option<Value>,
+    ) -> Result<Reply, ApplyError> {
+        let protocol = |reason: String| ApplyError::Protocol {
+            command: command.to_string(),
+            reason,
+        };
+        let request = match argument {
+            Some(argument) => {
+                Value::Object([(command.to_string(), argument)].into_iter().collect())
+            }
+            None => Value::String(command.to_string()),
+        };
+        self.socket
+            .send(Message::Text(request.to_string()))
+            .await
+            .map_err(|error| protocol(error.to_string()))?;
+        loop {
+            let message = timeout(TIMEOUT, self.socket.next())
+                .await
+                .map_err(|_| protocol("timed out".to_string()))?
+                .ok_or_else(|| protocol("the connection was closed".to_string()))?
+                .map_err(|error| protocol(error.to_string()))?;
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(_) => return Err(protocol("the connection was closed".to_string())),
+                _ => continue,
+            };
+            let mut reply: HashMap<String, Reply> = serde_json::from_str(&text)
+                .map_err(|_| protocol(format!("unexpected answer {}", text)))?;
+            return reply
+                .remove(command)
+                .ok_or_else(|| protocol(format!("unexpected answer {}", text)));
+        }
+    }
+
+    async fn state(&mut self) -> Result<String, ApplyError> {
+        Ok(self.request("GetState", None).await?.message())
+    }
+}
+
+fn is_inactive(state: &str) -> bool {
+    state.eq_ignore_ascii_case("inactive")
+}
+
+// validates the configuration first and restores the previous one if CamillaDSP stops with it
+pub async fn apply_configuration(
+    url: &str,
+    configuration: &Configuration,
+    rollback: bool,
+) -> Result<ApplyReport> {
+    let config_json = serde_json::to_string(configuration)
+        .context("The configuration could not be serialized to JSON.")?;
+    let mut camilladsp = CamillaDsp::connect(url).await?;
+
+    let validation = camilladsp
+        .request("ValidateConfigJson", Some(config_json.clone().into()))
+        .await?;
+    if !validation.is_ok() {
+        return Err(ApplyError::Rejected {
+            message: validation.message(),
+        }
+        .into());
+    }
+
+    // without the current configuration a failed apply could not be undone
+    let previous = match rollback {
+        true => {
+            let reply = camilladsp.request("GetConfigJson", None).await?;
+            match (reply.is_ok(), reply.value.clone()) {
+                (true, Some(previous)) => Some(previous),
+                _ => {
+                    return Err(ApplyError::NoRollback {
+                        reason: reply.message(),
+                    }
+                    .into())
+                }
+            }
+        }
+        false => None,
+    };
+    let previous_state = camilladsp.state().await?;
+    let set = camilladsp
+        .request("SetConfigJson", Some(config_json.into()))
+        .await?;
+    if !set.is_ok() {
+        return Err(ApplyError::Rejected {
+            message: set.message(),
+        }
+        .into());
+    }
+
+    tokio::time::sleep(SETTLE_TIME).await;
+    let state = camilladsp.state().await?;
+    if is_inactive(&state) && !is_inactive(&previous_state) {
+        let reason = match camilladsp.request("GetStopReason", None).await {
+            Ok(reply) if reply.is_ok() => reply.message(),
+            _ => format!("the state is {}", state),
+        };
+        if let Some(previous) = previous {
+            if camilladsp
+                .request("SetConfigJson", Some(previous))
+                .await?
+                .is_ok()
+            {
+                return Err(ApplyError::RolledBack { reason }.into());
+            }
+        }
+        return Err(ApplyError::Failed { reason }.into());
+    }
+
+    Ok(ApplyReport {
+        url: url.to_string(),
+        validation: validation.result,
+        state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{net::TcpListener, task::JoinHandle};
+    use tokio_tungstenite::accept_async;
+
+    const PREVIOUS: &str = r#"{"pipeline":[]}"#;
+
+    // answers like CamillaDSP and keeps the commands it received
+    struct StandIn {
+        valid: bool,
+        // CamillaDSP stops with every configuration but the previous one
+        stops: bool,
+        loaded: Option<String>,
+        commands: Vec<String>,
+    }
+    impl StandIn {
+        fn new(valid: bool, stops: bool, loaded: Option<&str>) -> Self {
+            StandIn {
+                valid,
+                stops,
+                loaded: loaded.map(str::to_string),
+                commands: Vec::new(),
+            }
+        }
+
+        fn answer(&mut self, command: &str, argument: Option<Value>) -> Value {
+            let running = !self.stops || self.loaded.as_deref() == Some(PREVIOUS);
+            let (result, value): (&str, Value) = match command {
+                "ValidateConfigJson" if self.valid => ("Ok", "OK".into()),
+                "ValidateConfigJson" => ("Error", "unknown filter type".into()),
+                "GetConfigJson" => match &self.loaded {
+                    Some(config) => ("Ok", config.as_str().into()),
+                    None => ("Error", "no config loaded".into()),
+                },
+                "SetConfigJson" => {
+                    self.loaded = argument.and_then(|config| config.as_str().map(str::to_string));
+                    ("Ok", Value::Null)
+                }
+                "GetState" if running => ("Ok", "Running".into()),
+                "GetState" => ("Ok", "Inactive".into()),
+                "GetStopReason" => ("Ok", serde_json::json!({"CaptureError": "device busy"})),
+                _ => ("Error", "unknown command".into()),
+            };
+            serde_json::json!({ command: { "result": result, "value": value } })
+        }
+
+        async fn serve(mut self) -> (String, JoinHandle<Self>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let handle = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut socket = accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(text))) = socket.next().await {
+                    let (command, argument) = match serde_json::from_str(&text).unwrap() {
+                        Value::String(command) => (command, None),
+                        Value::Object(request) => {
+                            let (command, argument) = request.into_iter().next().unwrap();
+                            (command, Some(argument))
+                        }
+                        request => panic!("unexpected request {}", request),
+                    };
+                    self.commands.push(command.clone());
+                    let answer = self.answer(&command, argument);
+                    socket
+                        .send(Message::Text(answer.to_string()))
+                        .await
+                        .unwrap();
+                }
+                self
+            });
+            (url, handle)
+        }
+    }
+
+    fn configuration() -> Configuration {
+        serde_yaml::from_str("filters: {}\npipeline: []").unwrap()
+    }
+
+    async fn apply(stand_in: StandIn, rollback: bool) -> (Result<ApplyReport>, StandIn) {
+        let (url, handle) = stand_in.serve().await;
+        let result = apply_configuration(&url, &configuration(), rollback).await;
+        (result, handle.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn applies_a_valid_configuration() {
+        let (result, stand_in) = apply(StandIn::new(true, false, Some(PREVIOUS)), true).await;
+        let report = result.unwrap();
+        assert_eq!(report.validation, "Ok");
+        assert_eq!(report.state, "Running");
+        assert!(stand_in.commands.contains(&"SetConfigJson".to_string()));
+        assert_ne!(stand_in.loaded.as_deref(), Some(PREVIOUS));
+    }
+
+    #[tokio::test]
+    async fn does_not_load_a_rejected_configuration() {
+        let (result, stand_in) = apply(StandIn::new(false, false, Some(PREVIOUS)), true).await;
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApplyError>(),
+            Some(ApplyError::Rejected { message }) if message == "unknown filter type"
+        ));
+        assert!(!stand_in.commands.contains(&"SetConfigJson".to_string()));
+        assert_eq!(stand_in.loaded.as_deref(), Some(PREVIOUS));
+    }
+
+    #[tokio::test]
+    async fn restores_the_previous_configuration() {
+        let (result, stand_in) = apply(StandIn::new(true, true, Some(PREVIOUS)), true).await;
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApplyError>(),
+            Some(ApplyError::RolledBack { reason }) if reason == "CaptureError: device busy"
+        ));
+        assert_eq!(stand_in.loaded.as_deref(), Some(PREVIOUS));
+    }
+
+    #[tokio::test]
+    async fn keeps_a_failed_configuration_without_rollback() {
+        let (result, stand_in) = apply(StandIn::new(true, true, Some(PREVIOUS)), false).await;
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApplyError>(),
+            Some(ApplyError::Failed { .. })
+        ));
+        assert_ne!(stand_in.loaded.as_deref(), Some(PREVIOUS));
+    }
+
+    #[tokio::test]
+    async fn does_not_apply_without_a_configuration_to_restore() {
+        let (result, stand_in) = apply(StandIn::new(true, false, None), true).await;
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApplyError>(),
+            Some(ApplyError::NoRollback { reason }) if reason == "no config loaded"
+        ));
+        assert!(!stand_in.commands.contains(&"SetConfigJson".to_string()));
+        assert_eq!(stand_in.loaded, None);
+    }
+}